@@ -1,79 +1,263 @@
 extern crate structopt;
 use structopt::StructOpt;
-use tacklebox::random_local;
-use tacklebox::tcp::TcpSession;
-use tacklebox::udp::UdpSession;
-use tacklebox::Protocol;
-use tacklebox::Sender;
+use tacklebox::banner::{self, Grabber};
+use tacklebox::duration;
+use tacklebox::resolve::Resolver;
+use tacklebox::services::Services;
+use tacklebox::target::TargetSpec;
+use tacklebox::{loopback, random_local};
+use tacklebox::{Datagram, Error, Protocol, Session, SessionBuilder, WithSession};
+
+use crate::packet::PacketArgs;
+use crate::target::OrderArgs;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
     #[structopt(help = "Tranport protocol used")]
     pub protocol: Protocol,
-    #[structopt(short = "f", long = "from", help = "Socket to send datagrams from")]
+    #[structopt(short = "f", long = "from", help = "Host to send datagrams from")]
     pub local: Option<String>,
-    #[structopt(help = "Socket to send datagrams to")]
-    pub remote: String,
-    #[structopt(help = "Payload to send to remote host")]
-    pub payload: String,
+    #[structopt(help = "Host and ports to send datagrams to, e.g. host:80 or host:8000-8010")]
+    pub remote: TargetSpec,
+    #[structopt(
+        required_unless = "banner",
+        help = "Payload to send to remote host; with --banner, the probe to send"
+    )]
+    pub payload: Option<String>,
     #[structopt(short = "n", help = "Amount of datagrams to collect")]
-    pub amount: Option<u16>,
-    #[structopt(short, long, help = "Time to spend waiting for individual datagrams")]
-    pub time: Option<u32>,
+    pub amount: Option<u32>,
+    #[structopt(
+        short,
+        long,
+        default_value = "5s",
+        parse(try_from_str = duration::parse),
+        help = "Time to spend waiting for individual datagrams (e.g. 250ms, 1.5s, 2m)"
+    )]
+    pub time: Duration,
+    #[structopt(
+        short = "N",
+        long = "numeric",
+        help = "Numeric-only addresses; skip DNS lookups"
+    )]
+    pub numeric: bool,
+    #[structopt(long, help = "Send as fast as possible and report packets per second")]
+    pub flood: bool,
+    #[structopt(
+        short,
+        long,
+        default_value = "64",
+        help = "Most datagrams to send or read per syscall when flooding"
+    )]
+    pub batch: usize,
+    #[structopt(long, help = "Let the kernel segment flooded UDP sends (Linux)")]
+    pub gso: bool,
+    #[structopt(
+        long,
+        help = "Let the kernel coalesce UDP datagrams into larger reads (Linux)"
+    )]
+    pub gro: bool,
+    #[structopt(
+        long,
+        help = "Send crafted IP packets on a raw socket (needs root or CAP_NET_RAW)"
+    )]
+    pub raw: bool,
+    #[structopt(long, help = "Connect, grab the service's banner and name the service")]
+    pub banner: bool,
+    #[structopt(flatten)]
+    pub order: OrderArgs,
+    #[structopt(flatten)]
+    pub packet: PacketArgs,
+}
+
+pub fn run(options: &mut Options) -> Result<(), Error> {
+    options.amount.get_or_insert(5);
+
+    if options.banner {
+        return banner(options);
+    }
+
+    options.protocol.dispatch(&*options)
+}
+
+impl Options {
+    fn payload(&self) -> &str {
+        self.payload.as_deref().unwrap_or_default()
+    }
 }
 
-pub fn run(options: &mut Options) {
-    let amount = *options.amount.get_or_insert(5);
-    let wait_time = *options.time.get_or_insert(5);
-
-    let local_writer = match &options.local {
-        Some(local) => local.clone(),
-        None => random_local(),
-    };
-
-    match options.protocol {
-        Protocol::Udp => {
-            let mut writer = UdpSession::new(&local_writer[..]);
-            writer.set_remote(&options.remote[..]);
-
-            let mut local_reader = String::from("127.0.0.1:");
-            local_reader.push_str(&writer.remote_port.unwrap().to_string()[..]);
-            let reader = UdpSession::new(&local_reader[..]);
-
-            print_header();
-
-            for i in 0..amount {
-                writer
-                    .send(options.payload.as_bytes())
-                    .expect("send failed!");
-                let (packet, receive_time) = reader.receive(wait_time).expect("receive failed!");
-                let packet_data = String::from_utf8_lossy(&packet.data);
-                print_stats(i, receive_time, packet_data.to_string().as_bytes().len());
+/// Grabs a banner from each port of the remote instead of sending
+/// the payload to ourselves.
+fn banner(options: &Options) -> Result<(), Error> {
+    if options.protocol != Protocol::Tcp {
+        return Err(Error::Unsupported("banner grabbing over UDP"));
+    }
+
+    let resolver = Resolver::new().numeric(options.numeric);
+    let ip = crate::resolve_host(&resolver, &options.remote)?;
+    let grabber = Grabber::new()
+        .timeout(options.time)
+        .payload(options.payload.as_ref().map(String::as_bytes));
+    // A closed or silent port gets its error printed in place of a
+    // banner; only a single port's error is the command's.
+    let ports = options.order.ports(&options.remote.ports);
+    for &port in &ports {
+        let remote = SocketAddr::new(ip, port);
+        let banner = match grabber.grab(remote) {
+            Err(e) if ports.len() == 1 => return Err(e),
+            banner => banner,
+        };
+        println!();
+        let label = Services::system().label(port, Protocol::Tcp);
+        println!("{:<9}: {} ({})", "TARGET", remote, label);
+        match banner {
+            Ok(banner) => {
+                println!("{:<9}: {}", "SERVICE", crate::scan::describe(Some(&banner)));
+                println!("{:<9}: {}", "BANNER", banner::escape(&banner.data));
             }
+            Err(e) => println!("{:<9}: {}", "ERROR", e),
         }
-        Protocol::Tcp => {
-            let mut writer = TcpSession::new(&local_writer[..]);
-            let connection = writer.connect_to(&options.remote[..]);
-            if connection.is_err() {
-                let connect_err = connection.unwrap_err();
-                println!("\nConnection not established : {}", connect_err);
-                return;
+    }
+    Ok(())
+}
+
+impl WithSession for &Options {
+    type Output = Result<(), Error>;
+
+    fn run<S: Session>(self) -> Self::Output {
+        let resolver = Resolver::new().numeric(self.numeric);
+        let ip = crate::resolve_host(&resolver, &self.remote)?;
+        let local = match &self.local {
+            Some(local) => crate::resolve(&resolver, local)?,
+            None => random_local(ip),
+        };
+
+        // Each port gets its own run, one after the other.
+        let ports = self.order.ports(&self.remote.ports);
+        for &port in &ports {
+            let remote = SocketAddr::new(ip, port);
+            if ports.len() > 1 {
+                println!(
+                    "\n{} ({})",
+                    remote,
+                    Services::system().label(port, S::PROTOCOL)
+                );
             }
+            cast::<S>(self, local, remote)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends the payload from `local` to `remote` and reports what comes
+/// back.
+fn cast<S: Session>(options: &Options, local: SocketAddr, remote: SocketAddr) -> Result<(), Error> {
+    let amount = options.amount.unwrap_or(5);
+    let wait_time = options.time;
+
+    // The reader has to be listening before a connection-oriented
+    // writer can reach it.
+    let mut reader: S = SessionBuilder::new()
+        .local(SocketAddr::new(loopback(remote.ip()), remote.port()))
+        .gro(options.gro)
+        .raw(options.raw)
+        .build()?;
+    let mut writer: S = SessionBuilder::new()
+        .local(local)
+        .remote(remote)
+        .packet_options(options.packet.options())
+        .gso(options.gso)
+        .raw(options.raw)
+        .build()?;
+    reader.accept(wait_time)?;
+
+    if options.flood {
+        return flood(options, writer, reader, amount);
+    }
+
+    print_header();
+
+    for i in 0..amount {
+        writer.send(options.payload().as_bytes())?;
+        let (packet, receive_time) = reader.receive(wait_time)?;
+        print_stats(i, receive_time, packet.len());
+    }
+    Ok(())
+}
+
+/// Sends `amount` copies of the payload in batches while a second
+/// thread drains the reader, then reports the rate on each side.
+fn flood<S: Session>(
+    options: &Options,
+    mut writer: S,
+    mut reader: S,
+    amount: u32,
+) -> Result<(), Error> {
+    let batch = options.batch.max(1);
+    let wait_time = options.time;
 
-            let mut local_reader = String::from("127.0.0.1:");
-            local_reader.push_str(&writer.remote_port.unwrap().to_string()[..]);
-            let mut reader = TcpSession::new(&local_reader[..]);
-
-            for _ in 0..amount {
-                writer
-                    .send(options.payload.as_bytes())
-                    .expect("send failed!");
-                let (packet, _bytes) = reader.receive(wait_time).expect("receive failed!");
-                let packet_data = String::from_utf8_lossy(&packet.data);
-                println!("{}", packet_data);
+    let drain = thread::spawn(move || -> Result<(usize, Duration), Error> {
+        let mut received = 0;
+        let now = Instant::now();
+        let mut last_packet = now;
+        while received < amount as usize {
+            match reader.receive_count(batch, wait_time) {
+                Ok((count, _bytes)) => {
+                    received += count;
+                    last_packet = Instant::now();
+                }
+                Err(Error::Timeout(_)) => break,
+                Err(e) => return Err(e),
             }
         }
+        Ok((received, last_packet - now))
+    });
+
+    let payloads = vec![options.payload().as_bytes(); batch];
+    let mut sent = 0;
+    let now = Instant::now();
+    while sent < amount as usize {
+        let count = batch.min(amount as usize - sent);
+        sent += writer.send_many(&payloads[..count])?;
     }
+    let send_time = now.elapsed();
+
+    let (received, receive_time) = drain.join().expect("receive thread panicked")?;
+
+    print_rate_header();
+    print_rate("sent", sent, send_time);
+    print_rate("received", received, receive_time);
+    println!();
+    Ok(())
+}
+
+fn print_rate_header() {
+    println!();
+
+    println!(
+        "{direction:<width$}{packets:<width$}{time:<width$}{rate:<width$}",
+        direction = "DIRECTION",
+        packets = "PACKETS",
+        time = "TIME",
+        rate = "PACKETS/SEC",
+        width = 20
+    );
+}
+
+fn print_rate(direction: &str, packets: usize, elapsed: Duration) {
+    let rate = packets as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+    println!(
+        "{direction:<width$}{packets:<width$}{time:<width$}{rate:<width$.0}",
+        direction = direction,
+        packets = packets,
+        time = duration::format(elapsed),
+        rate = rate,
+        width = 20
+    );
 }
 
 fn print_header() {
@@ -88,9 +272,8 @@ fn print_header() {
     );
 }
 
-fn print_stats(packet_number: u16, wait_time: u32, bytes: usize) {
-    let mut wait_time = wait_time.to_string();
-    wait_time.push_str("s");
+fn print_stats(packet_number: u32, wait_time: Duration, bytes: usize) {
+    let wait_time = duration::format(wait_time);
 
     println!(
         "{number:<width$}{time:<width$}{bytes:<width$}",
@@ -1,81 +1,149 @@
 extern crate structopt;
 use structopt::StructOpt;
-use tacklebox::tcp::{TcpDatagram, TcpSession};
-use tacklebox::udp::{UdpDatagram, UdpSession};
-use tacklebox::Protocol;
+use tacklebox::duration;
+use tacklebox::etherparse::IpHeader;
+use tacklebox::ipopt::IpOptions;
+use tacklebox::resolve::Resolver;
+use tacklebox::target::TargetSpec;
+use tacklebox::{Datagram, Error, Protocol, Session, SessionBuilder, WithSession};
 
-use std::io::ErrorKind as IoErrKind;
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
     #[structopt(help = "Tranport protocol used")]
     pub protocol: Protocol,
-    #[structopt(help = "Socket to send datagrams from")]
-    pub local: String,
+    #[structopt(help = "Host and port to receive datagrams on")]
+    pub local: TargetSpec,
     #[structopt(short = "n", help = "Amount of datagrams to collect")]
-    pub amount: Option<u16>,
-    #[structopt(short, long, help = "Time to spend waiting for individual datagrams")]
-    pub time: Option<u32>,
+    pub amount: Option<u32>,
+    #[structopt(
+        short,
+        long,
+        default_value = "5s",
+        parse(try_from_str = duration::parse),
+        help = "Time to spend waiting for individual datagrams (e.g. 250ms, 1.5s, 2m)"
+    )]
+    pub time: Duration,
+    #[structopt(
+        short = "N",
+        long = "numeric",
+        help = "Numeric-only addresses; skip DNS lookups"
+    )]
+    pub numeric: bool,
+    #[structopt(
+        short,
+        long,
+        default_value = "64",
+        help = "Most datagrams to read per syscall"
+    )]
+    pub batch: usize,
+    #[structopt(
+        long,
+        help = "Let the kernel coalesce UDP datagrams into larger reads (Linux)"
+    )]
+    pub gro: bool,
+    #[structopt(
+        long,
+        help = "Read on a raw socket and decode the real headers (needs root or CAP_NET_RAW)"
+    )]
+    pub raw: bool,
 }
 
-pub fn run(options: &mut Options) {
-    let amount = *options.amount.get_or_insert(5);
-    let wait_time = *options.time.get_or_insert(5);
+pub fn run(options: &mut Options) -> Result<(), Error> {
+    options.amount.get_or_insert(5);
 
-    match options.protocol {
-        Protocol::Udp => {
-            let session = UdpSession::new(&options.local[..]);
-            let mut packets: Vec<UdpDatagram> = Vec::new();
+    options.protocol.dispatch(&*options)
+}
+
+impl WithSession for &Options {
+    type Output = Result<(), Error>;
+
+    fn run<S: Session>(self) -> Self::Output {
+        let amount = self.amount.unwrap_or(5);
+        let wait_time = self.time;
+
+        let resolver = Resolver::new().numeric(self.numeric);
+        let port = self.local.single_port()?;
+        let local = SocketAddr::new(crate::resolve_host(&resolver, &self.local)?, port);
+        let mut session: S = SessionBuilder::new()
+            .local(local)
+            .gro(self.gro)
+            .raw(self.raw)
+            .build()?;
+
+        if let Some(s) = session.accept(wait_time)? {
+            println!(
+                "{} connection esstablished in : {}",
+                S::PROTOCOL,
+                duration::format(s)
+            );
+        }
 
-            let mut total_bytes = 0;
+        // Every timeout uses up one of the `amount` attempts, the same
+        // as a datagram does.
+        let mut remaining = amount as usize;
+        let mut packets = 0;
+        let mut total_bytes = 0;
+        let mut total_time = Duration::ZERO;
+        let mut first_packet = None;
+        let mut last_packet = Instant::now();
+        while remaining > 0 {
             let now = Instant::now();
-            for _ in 0..amount {
-                match session.receive(wait_time) {
-                    Ok((packet, bytes)) => {
-                        packets.push(packet);
-                        total_bytes += bytes;
-                    }
-                    Err(ref e) if e.kind() != IoErrKind::TimedOut => {
-                        println!("Unexpected Error : {}\n", *e);
-                        return;
-                    }
-                    Err(_) => {}
+            let max = remaining.min(self.batch);
+            // Only raw reads have IP options to show; otherwise just
+            // count, straight out of the receive buffers.
+            let received = if self.raw {
+                session.receive_many(max, wait_time).map(|batch| {
+                    batch.iter().for_each(print_ip_options);
+                    (batch.len(), batch.iter().map(Datagram::len).sum())
+                })
+            } else {
+                session.receive_count(max, wait_time)
+            };
+            match received {
+                Ok((count, bytes)) => {
+                    last_packet = Instant::now();
+                    first_packet.get_or_insert(last_packet);
+                    total_time += last_packet - now;
+                    remaining -= count;
+                    packets += count;
+                    total_bytes += bytes;
                 }
+                Err(Error::Timeout(_)) => remaining -= 1,
+                Err(e) => return Err(e),
             }
-            let total_time = now.elapsed().as_secs();
-            let received = packets.len() as f32 / amount as f32;
-
-            let avg_time = total_time as f32 / received;
-
-            print_header();
-            print_stats(avg_time, total_bytes, received);
         }
-        Protocol::Tcp => {
-            let mut session = TcpSession::new(&options.local[..]);
-            let mut packets: Vec<TcpDatagram> = Vec::new();
-
-            match session.wait_for_connection(wait_time) {
-                Ok(s) => println!("TCP connection esstablished in : {} seconds", s),
-                Err(e) => {
-                    println!("\nTCP connection not established : {}\n", e);
-                    return;
-                }
-            };
-
-            let mut total_bytes = 0;
-            let now = Instant::now();
-            for _ in 0..amount {
-                let (packet, bytes) = session.receive(wait_time).unwrap();
-                packets.push(packet);
-                total_bytes += bytes;
+        let received = packets as f32 / amount as f32;
+
+        let avg_time = match packets {
+            0 => Duration::ZERO,
+            n => total_time / n as u32,
+        };
+
+        print_header();
+        print_stats(avg_time, total_bytes, received);
+        match first_packet {
+            Some(first_packet) if last_packet > first_packet => {
+                print_rate(packets, last_packet - first_packet)
             }
-            let read_time = now.elapsed().as_secs() as u32;
-            let received = packets.len() as f32 / amount as f32;
-            let avg_time = read_time as f32 / received;
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
-            print_header();
-            print_stats(avg_time, total_bytes, received);
+/// Shows the IPv4 options a datagram carried; only raw sessions can
+/// see them.
+fn print_ip_options<D: Datagram>(datagram: &D) {
+    if let Some(IpHeader::Version4(header)) = datagram.ip_header() {
+        if header.options().is_empty() {
+            return;
+        }
+        match IpOptions::decode(header.options()) {
+            Ok(options) => println!("IP options : {}", options),
+            Err(e) => println!("IP options : {}", e),
         }
     }
 }
@@ -92,12 +160,11 @@ fn print_header() {
     );
 }
 
-fn print_stats(avg_time: f32, total_bytes: u32, received: f32) {
+fn print_stats(avg_time: Duration, total_bytes: usize, received: f32) {
     let mut received = received.to_string();
-    received.push_str("%");
+    received.push('%');
 
-    let mut avg_time = avg_time.to_string();
-    avg_time.push_str("s");
+    let avg_time = duration::format(avg_time);
 
     println!(
         "{time:<width$}{bytes:<width$}{received:<width$}",
@@ -109,3 +176,13 @@ fn print_stats(avg_time: f32, total_bytes: u32, received: f32) {
 
     println!();
 }
+
+fn print_rate(packets: usize, elapsed: Duration) {
+    let rate = packets as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} packets in {} : {:.0} packets/sec\n",
+        packets,
+        duration::format(elapsed),
+        rate
+    );
+}
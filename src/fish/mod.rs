@@ -1,122 +1,813 @@
 extern crate structopt;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io::{self, IsTerminal};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
+use tacklebox::diag::{self, TcpInfo};
+use tacklebox::duration;
+use tacklebox::interrupt;
+use tacklebox::procnet::{self, ProcNet, Process, Socket, SocketState};
+use tacklebox::services::Services;
+use tacklebox::target::PortRange;
+use tacklebox::{Error, Protocol};
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
-    #[structopt(help = "Port to scan")]
-    pub ports: String,
+    #[structopt(
+        required_unless = "top",
+        help = "Ports to scan, e.g. 22 or 20-25,80,8000-8100; every port if left out with --top"
+    )]
+    pub ports: Option<PortRange>,
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Only list sockets in these states, e.g. listen,established,time-wait"
+    )]
+    pub state: Vec<SocketState>,
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Only list sockets these processes hold"
+    )]
+    pub pid: Vec<u32>,
+    #[structopt(long, help = "Only list sockets owned by this user name or uid")]
+    pub user: Option<String>,
+    #[structopt(long, help = "Only list tcp or udp sockets")]
+    pub protocol: Option<Protocol>,
+    #[structopt(
+        short,
+        long,
+        help = "Show RTT, cwnd, retransmits, bytes and pacing for each TCP connection"
+    )]
+    pub verbose: bool,
+    #[structopt(
+        short,
+        long,
+        help = "Keep polling, printing sockets that open, close or change and their traffic"
+    )]
+    pub watch: bool,
+    #[structopt(
+        long,
+        conflicts_with = "watch",
+        help = "Show a live table of each process's TCP send and receive rates, busiest first"
+    )]
+    pub top: bool,
+    #[structopt(
+        long,
+        default_value = "1s",
+        parse(try_from_str = duration::parse),
+        help = "Time between polls with --watch or --top (e.g. 250ms, 1.5s, 2m)"
+    )]
+    pub interval: Duration,
 }
 
-pub fn run(options: &mut Options) {
-    let mut ports = String::from(":");
-    ports.push_str(&options.ports);
+impl Options {
+    /// Whether `port` is one being fished for.
+    fn fishes_for(&self, port: u16) -> bool {
+        self.ports.as_ref().is_none_or(|ports| ports.contains(port))
+    }
+}
+
+/// The sockets on the fished-for ports at one moment, with what is
+/// known about their owners.
+struct Snapshot {
+    sockets: Vec<Socket>,
+    users: HashMap<u32, Option<String>>,
+    processes: HashMap<u32, Option<Process>>,
+    /// Empty unless `--verbose`, `--watch` or `--top` asked for them.
+    infos: HashMap<u64, TcpInfo>,
+}
+
+/// Identifies a socket across snapshots; inodes won't do, as sockets
+/// in TIME_WAIT have none.
+type Key = (Protocol, SocketAddr, SocketAddr);
+
+impl Snapshot {
+    fn take(proc_net: &ProcNet, options: &Options) -> Result<Snapshot, Error> {
+        let mut sockets: Vec<Socket> = proc_net
+            .sockets()?
+            .into_iter()
+            .filter(|socket| {
+                options.fishes_for(socket.local.port())
+                    || (socket.is_connected() && options.fishes_for(socket.remote.port()))
+            })
+            .filter(|socket| options.state.is_empty() || options.state.contains(&socket.state))
+            .filter(|socket| {
+                options
+                    .protocol
+                    .is_none_or(|protocol| socket.protocol == protocol)
+            })
+            .filter(|socket| {
+                options.pid.is_empty() || socket.pids.iter().any(|pid| options.pid.contains(pid))
+            })
+            .collect();
+
+        // Users and processes are looked up once each, however many
+        // sockets they have.
+        let mut users: HashMap<u32, Option<String>> = HashMap::new();
+        let mut processes: HashMap<u32, Option<Process>> = HashMap::new();
+        for socket in &sockets {
+            users
+                .entry(socket.uid)
+                .or_insert_with(|| procnet::user_name(socket.uid));
+            for pid in &socket.pids {
+                processes
+                    .entry(*pid)
+                    .or_insert_with(|| proc_net.process(*pid));
+            }
+        }
+        if let Some(user) = &options.user {
+            sockets.retain(|socket| {
+                *user == socket.uid.to_string() || users[&socket.uid].as_deref() == Some(user)
+            });
+        }
+        sockets.sort_by_key(|socket| (socket.pids.first().copied(), socket.local, socket.remote));
+
+        let infos = match options.verbose || options.watch || options.top {
+            true => diag::tcp_info()?,
+            false => HashMap::new(),
+        };
+        Ok(Snapshot {
+            sockets,
+            users,
+            processes,
+            infos,
+        })
+    }
+
+    fn info(&self, socket: &Socket) -> Option<&TcpInfo> {
+        self.infos.get(&socket.inode).filter(|_| socket.inode != 0)
+    }
 
-    // `lsof` tends to take a little bit,
+    fn by_key(&self) -> HashMap<Key, &Socket> {
+        self.sockets
+            .iter()
+            .map(|socket| ((socket.protocol, socket.local, socket.remote), socket))
+            .collect()
+    }
+
+    /// The first process holding `socket`, as `1234/python3`.
+    fn owner(&self, socket: &Socket) -> String {
+        let pid = match socket.pids.first() {
+            Some(pid) => *pid,
+            None => return String::from("-"),
+        };
+        match &self.processes[&pid] {
+            Some(process) => format!("{}/{}", pid, process.command),
+            None => pid.to_string(),
+        }
+    }
+}
+
+pub fn run(options: &mut Options) -> Result<(), Error> {
+    // Walking every process's descriptors can take a little bit,
     // so why don't we time it?
     let now = std::time::Instant::now();
-    let lsof_output = Command::new("lsof")
-        .arg("-i")
-        .arg(&ports[..])
-        .stdout(Stdio::piped())
-        .output()
-        .expect("Command `lsof` failed; try checking if it is installed");
-    let lsof_time = now.elapsed().as_secs();
+    let proc_net = ProcNet::new();
+    let snapshot = Snapshot::take(&proc_net, options)?;
+    let proc_time = now.elapsed();
+
+    if options.top {
+        return top(options, &proc_net, snapshot);
+    }
 
     println!(
-        "\nProcess and address information collected in : {} seconds",
-        lsof_time
+        "\nProcess and address information collected in : {}",
+        duration::format(proc_time)
     );
 
-    let lsof_output = String::from_utf8_lossy(&lsof_output.stdout);
-
-    let mut file = File::create("lsof.txt").expect("Failed to create `lsof.txt`");
-    let _ = file
-        .write_all(lsof_output.as_bytes())
-        .expect("Failed to write to `lsof.txt`");
-
-    let pid_output = Command::new("awk")
-        .arg("NR>1 {print $2}")
-        .arg("pid.txt")
-        .output()
-        .expect("Command `awk` failed");
-
-    let pid_output = String::from_utf8_lossy(&pid_output.stdout);
-    //FIXME calling `unwrap_or_default()` here will return a 0 if there is an error parsing the string.
-    //given that the root pid is 0, this should be fixed.
-    let pids: Vec<u32> = pid_output
-        .split("\n")
-        .map(|x| x.parse::<u32>().ok().unwrap_or_default())
-        .collect();
-
-    let port_output = Command::new("awk")
-        .arg("NR>1 {print $9}")
-        .arg("pid.txt")
-        .output()
-        .expect("Command `awk` failed");
-
-    let port_output = String::from_utf8_lossy(&port_output.stdout);
-    let address_pairs: Vec<&str> = port_output.split("\n").collect();
-
-    // A map of pids to source/destination ports
-    let mut port_proc_map: HashMap<u32, Vec<(&str, &str)>> = HashMap::new();
-    for (i, pid) in pids.iter().enumerate() {
-        let mut address_pair: Vec<&str> = address_pairs[i].split("->").collect();
-        if address_pair.len() == 1 {
-            address_pair.push("");
+    print_header();
+    for socket in &snapshot.sockets {
+        let label = label(options, socket);
+        let user = match &snapshot.users[&socket.uid] {
+            Some(name) => name.clone(),
+            None => socket.uid.to_string(),
+        };
+
+        // Sockets no process can be seen holding, like those in
+        // TIME_WAIT, still get a row.
+        let info = snapshot.info(socket).filter(|_| options.verbose);
+        if socket.pids.is_empty() {
+            print_stats(socket, None, None, &user, &label);
+            print_info(info);
         }
-        if port_proc_map.contains_key(&pid) {
-            port_proc_map
-                .get_mut(&pid)
-                .unwrap()
-                .push((address_pair[0], address_pair[1]))
-        } else {
-            let mut pairs = Vec::new();
-            pairs.push((address_pair[0], address_pair[1]));
-            port_proc_map.insert(pid.clone(), pairs);
+        for pid in &socket.pids {
+            if options.pid.is_empty() || options.pid.contains(pid) {
+                let process = snapshot.processes[pid].as_ref();
+                print_stats(socket, Some(*pid), process, &user, &label);
+                print_info(info);
+            }
         }
     }
+    println!();
 
-    print_header();
+    if options.watch {
+        watch(options, &proc_net, snapshot)?;
+    }
+    Ok(())
+}
+
+/// Polls every `--interval` until Ctrl-C, printing sockets that open,
+/// close or change state, and how much each TCP connection sent and
+/// received in between.
+fn watch(options: &Options, proc_net: &ProcNet, mut previous: Snapshot) -> Result<(), Error> {
+    interrupt::catch()?;
+    println!(
+        "Watching every {} (times in UTC); press Ctrl-C to stop",
+        duration::format(options.interval)
+    );
+    print_event_header();
 
-    let pids: Vec<&u32> = port_proc_map.keys().collect();
-    for pid in pids {
-        let pairs: Vec<(&str, &str)> = port_proc_map.get(pid).unwrap().to_vec();
-        for pair in pairs {
-            //FIXME a pid of 0 is invalid in this context; this check is
-            // ideally not necessary.
-            if *pid != 0 {
-                print_stats(*pid, pair.0, pair.1);
+    let started = Instant::now();
+    let (mut opened, mut closed) = (0, 0);
+    while interrupt::sleep(options.interval) {
+        let current = Snapshot::take(proc_net, options)?;
+        let time = clock(SystemTime::now());
+        for event in events(&previous, &current) {
+            match event {
+                Event::Opened(socket) => {
+                    opened += 1;
+                    let detail = format!("{} {}", socket.state, current.owner(socket));
+                    print_event(&time, "open", options, socket, &detail);
+                }
+                Event::State { socket, from } => {
+                    let detail = format!("{} -> {}", from, socket.state);
+                    print_event(&time, "state", options, socket, &detail);
+                }
+                Event::Data { socket, traffic } => {
+                    print_event(&time, "data", options, socket, &traffic.to_string());
+                }
+                Event::Closed(socket) => {
+                    closed += 1;
+                    let detail = format!("{} {}", socket.state, previous.owner(socket));
+                    print_event(&time, "close", options, socket, &detail);
+                }
             }
         }
+        previous = current;
+    }
+
+    println!(
+        "\n{} opened, {} closed in {}",
+        opened,
+        closed,
+        duration::format(started.elapsed())
+    );
+    Ok(())
+}
+
+/// A process's share of the traffic between two polls.
+#[derive(Debug, Default, PartialEq)]
+struct Usage {
+    command: String,
+    user: String,
+    sent: u64,
+    received: u64,
+    connections: usize,
+}
+
+/// Polls every `--interval` until Ctrl-C, redrawing a table of how
+/// fast each process is sending and receiving over TCP.
+fn top(options: &Options, proc_net: &ProcNet, mut previous: Snapshot) -> Result<(), Error> {
+    interrupt::catch()?;
+    let mut polled = Instant::now();
+    while interrupt::sleep(options.interval) {
+        let current = Snapshot::take(proc_net, options)?;
+        let elapsed = polled.elapsed();
+        polled = Instant::now();
+        print_top(options, elapsed, &usage(&previous, &current));
+        previous = current;
+    }
+    Ok(())
+}
+
+/// The TCP traffic each process moved from `previous` to `current`,
+/// busiest first, with ties in PID order. Each socket's traffic goes
+/// to the first process holding it.
+fn usage(previous: &Snapshot, current: &Snapshot) -> Vec<(Option<u32>, Usage)> {
+    let before = previous.by_key();
+
+    let mut usage: HashMap<Option<u32>, Usage> = HashMap::new();
+    for socket in &current.sockets {
+        // Listening sockets carry no traffic of their own.
+        let info = match current.info(socket) {
+            Some(info) if socket.is_connected() => info,
+            _ => continue,
+        };
+        // Sockets that opened since the last poll count from zero.
+        let key = (socket.protocol, socket.local, socket.remote);
+        let zero = TcpInfo::default();
+        let old = before
+            .get(&key)
+            .and_then(|old| previous.info(old))
+            .unwrap_or(&zero);
+        let traffic = Traffic::between(old, info);
+
+        let pid = socket.pids.first().copied();
+        let entry = usage.entry(pid).or_insert_with(|| Usage {
+            command: pid
+                .and_then(|pid| current.processes[&pid].as_ref())
+                .map_or_else(|| String::from("-"), |process| process.command.clone()),
+            user: current.users[&socket.uid]
+                .clone()
+                .unwrap_or_else(|| socket.uid.to_string()),
+            ..Usage::default()
+        });
+        entry.sent += traffic.bytes_out;
+        entry.received += traffic.bytes_in;
+        entry.connections += 1;
     }
 
+    let mut usage: Vec<(Option<u32>, Usage)> = usage.into_iter().collect();
+    usage.sort_by_key(|(pid, usage)| (std::cmp::Reverse(usage.sent + usage.received), *pid));
+    usage
+}
+
+fn print_top(options: &Options, elapsed: Duration, usage: &[(Option<u32>, Usage)]) {
+    // Redraw in place on a terminal; keep every table when piped.
+    if io::stdout().is_terminal() {
+        print!("\x1b[2J\x1b[H");
+    } else {
+        println!();
+    }
+    let ports = match &options.ports {
+        Some(ports) => format!("ports {}", ports),
+        None => String::from("all ports"),
+    };
+    println!(
+        "TCP traffic on {} per process, every {}; press Ctrl-C to stop",
+        ports,
+        duration::format(options.interval)
+    );
     println!();
+
+    println!(
+        "{pid:<8}{user:<10}{command:<16}{sent:<14}{received:<14}CONNECTIONS",
+        pid = "PID",
+        user = "USER",
+        command = "COMMAND",
+        sent = "SENT",
+        received = "RECEIVED",
+    );
+    let per_sec = |bytes: u64| rate((bytes as f64 / elapsed.as_secs_f64()) as u64);
+    for (pid, usage) in usage {
+        println!(
+            "{pid:<8}{user:<10}{command:<16}{sent:<14}{received:<14}{connections}",
+            pid = pid.map_or_else(|| String::from("-"), |pid| pid.to_string()),
+            user = usage.user,
+            command = usage.command,
+            sent = per_sec(usage.sent),
+            received = per_sec(usage.received),
+            connections = usage.connections
+        );
+    }
+
+    let sent = usage.iter().map(|(_, usage)| usage.sent).sum();
+    let received = usage.iter().map(|(_, usage)| usage.received).sum();
+    println!(
+        "\nTOTAL{:<29}{sent:<14}{received}",
+        "",
+        sent = per_sec(sent),
+        received = per_sec(received)
+    );
+}
+
+/// Something `--watch` reports about a socket between two polls.
+#[derive(Debug, PartialEq)]
+enum Event<'a> {
+    Opened(&'a Socket),
+    State {
+        socket: &'a Socket,
+        from: SocketState,
+    },
+    Data {
+        socket: &'a Socket,
+        traffic: Traffic,
+    },
+    Closed(&'a Socket),
+}
+
+/// What changed from `previous` to `current`: sockets that opened,
+/// changed state or moved data, in `current`'s order, then those that
+/// closed, in `previous`'s.
+fn events<'a>(previous: &'a Snapshot, current: &'a Snapshot) -> Vec<Event<'a>> {
+    let before = previous.by_key();
+    let after = current.by_key();
+
+    let mut events = Vec::new();
+    for socket in &current.sockets {
+        let old = match before.get(&(socket.protocol, socket.local, socket.remote)) {
+            Some(old) => old,
+            None => {
+                events.push(Event::Opened(socket));
+                continue;
+            }
+        };
+        if old.state != socket.state {
+            events.push(Event::State {
+                socket,
+                from: old.state,
+            });
+        }
+        if let (Some(old), Some(new)) = (previous.info(old), current.info(socket)) {
+            let traffic = Traffic::between(old, new);
+            if !traffic.is_idle() {
+                events.push(Event::Data { socket, traffic });
+            }
+        }
+    }
+    for socket in &previous.sockets {
+        if !after.contains_key(&(socket.protocol, socket.local, socket.remote)) {
+            events.push(Event::Closed(socket));
+        }
+    }
+    events
+}
+
+/// What a TCP connection sent and received between two polls.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Traffic {
+    bytes_out: u64,
+    bytes_in: u64,
+    segs_out: u32,
+    segs_in: u32,
+}
+
+impl Traffic {
+    /// The traffic from `old` to `new`. The segment counters are 32
+    /// bits and wrap. The byte counters are 64 bits and don't, so if
+    /// one went down, a new connection took the same addresses between
+    /// polls and everything counts from zero.
+    fn between(old: &TcpInfo, new: &TcpInfo) -> Traffic {
+        if new.bytes_acked < old.bytes_acked || new.bytes_received < old.bytes_received {
+            return Traffic::between(&TcpInfo::default(), new);
+        }
+        Traffic {
+            bytes_out: new.bytes_acked - old.bytes_acked,
+            bytes_in: new.bytes_received - old.bytes_received,
+            segs_out: new.segs_out.wrapping_sub(old.segs_out),
+            segs_in: new.segs_in.wrapping_sub(old.segs_in),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        *self == Traffic::default()
+    }
+}
+
+impl Display for Traffic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "out +{} bytes +{} packets, in +{} bytes +{} packets",
+            self.bytes_out, self.segs_out, self.bytes_in, self.segs_in
+        )
+    }
+}
+
+/// Labels whichever end is being fished for; the local end if both.
+fn label(options: &Options, socket: &Socket) -> String {
+    let port = match options.fishes_for(socket.local.port()) {
+        true => socket.local.port(),
+        false => socket.remote.port(),
+    };
+    Services::system().label(port, socket.protocol)
+}
+
+/// `time` as `14:03:09.123`, in UTC.
+fn clock(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn print_event_header() {
+    println!();
+
+    println!(
+        "{time:<14}{event:<8}{port:<20}{source:<width$}{destination:<width$}DETAIL",
+        time = "TIME",
+        event = "EVENT",
+        port = "PORT",
+        source = "SOURCE",
+        destination = "DESTINATION",
+        width = 30
+    );
+}
+
+fn print_event(time: &str, event: &str, options: &Options, socket: &Socket, detail: &str) {
+    let destination = match socket.is_connected() {
+        true => socket.remote.to_string(),
+        false => String::from("-"),
+    };
+
+    println!(
+        "{time:<14}{event:<8}{port:<20}{source:<width$}{destination:<width$}{detail}",
+        time = time,
+        event = event,
+        port = label(options, socket),
+        source = socket.local.to_string(),
+        destination = destination,
+        detail = detail,
+        width = 30
+    );
 }
 
 fn print_header() {
     println!();
 
     println!(
-        "{pid:<pid_width$}{source:<width$}{destination:<width$}",
+        "{pid:<8}{user:<10}{command:<16}{port:<20}{state:<13}{send:<8}{recv:<8}{inode:<10}\
+         {source:<width$}{destination:<width$}CMDLINE",
         pid = "PID",
+        user = "USER",
+        command = "COMMAND",
+        port = "PORT",
+        state = "STATE",
+        send = "SEND-Q",
+        recv = "RECV-Q",
+        inode = "INODE",
         source = "SOURCE",
         destination = "DESTINATION",
-        pid_width = 20,
-        width = 50
+        width = 30
     );
 }
 
-fn print_stats(pid: u32, source: &str, destination: &str) {
+fn print_stats(
+    socket: &Socket,
+    pid: Option<u32>,
+    process: Option<&Process>,
+    user: &str,
+    label: &str,
+) {
+    let destination = match socket.is_connected() {
+        true => socket.remote.to_string(),
+        false => String::from("-"),
+    };
+    let (command, cmdline) = match process {
+        Some(process) => (process.command.clone(), process.cmdline.join(" ")),
+        None => (String::from("-"), String::new()),
+    };
+
     println!(
-        "{pid:<pid_width$}{source:<width$}{destination:<width$}",
-        pid = pid,
-        source = source,
+        "{pid:<8}{user:<10}{command:<16}{port:<20}{state:<13}{send:<8}{recv:<8}{inode:<10}\
+         {source:<width$}{destination:<width$}{cmdline}",
+        pid = pid.map_or_else(|| String::from("-"), |pid| pid.to_string()),
+        user = user,
+        command = command,
+        port = label,
+        state = socket.state.to_string(),
+        send = socket.tx_queue,
+        recv = socket.rx_queue,
+        inode = socket.inode,
+        source = socket.local.to_string(),
         destination = destination,
-        pid_width = 20,
-        width = 50
+        cmdline = cmdline,
+        width = 30
     );
 }
+
+/// Prints a connection's metrics under its row, the way `ss -ti` does.
+fn print_info(info: Option<&TcpInfo>) {
+    let info = match info {
+        Some(info) => info,
+        None => return,
+    };
+
+    print!("        ");
+    if let Some(congestion) = &info.congestion {
+        print!("{} ", congestion);
+    }
+    print!(
+        "rtt:{}/{} cwnd:{} retrans:{} bytes_acked:{} bytes_received:{}",
+        duration::format(info.rtt),
+        duration::format(info.rtt_var),
+        info.cwnd,
+        info.retransmits,
+        info.bytes_acked,
+        info.bytes_received
+    );
+    if let Some(pacing_rate) = info.pacing_rate {
+        print!(" pacing_rate:{}", rate(pacing_rate));
+    }
+    println!();
+}
+
+/// Formats bytes per second as bits per second, e.g. `3.2Gbps`.
+fn rate(bytes_per_sec: u64) -> String {
+    let bits = bytes_per_sec as f64 * 8.0;
+    let units = [(1e9, "Gbps"), (1e6, "Mbps"), (1e3, "Kbps")];
+    match units.iter().find(|(scale, _)| bits >= *scale) {
+        Some((scale, unit)) => format!("{:.1}{}", bits / scale, unit),
+        None => format!("{}bps", bits),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(local: &str, remote: &str, state: SocketState, inode: u64) -> Socket {
+        Socket {
+            protocol: Protocol::Tcp,
+            local: local.parse().unwrap(),
+            remote: remote.parse().unwrap(),
+            state,
+            tx_queue: 0,
+            rx_queue: 0,
+            uid: 1000,
+            inode,
+            pids: vec![4242],
+        }
+    }
+
+    fn info(bytes_acked: u64, bytes_received: u64, segs_out: u32, segs_in: u32) -> TcpInfo {
+        TcpInfo {
+            bytes_acked,
+            bytes_received,
+            segs_out,
+            segs_in,
+            ..TcpInfo::default()
+        }
+    }
+
+    fn snapshot(sockets: Vec<Socket>, infos: Vec<(u64, TcpInfo)>) -> Snapshot {
+        Snapshot {
+            sockets,
+            users: HashMap::new(),
+            processes: HashMap::new(),
+            infos: infos.into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn top_sorts_processes_by_traffic() {
+        use SocketState::*;
+
+        let owned = |socket: Socket, pids: Vec<u32>| Socket { pids, ..socket };
+        let listener = owned(socket("0.0.0.0:80", "0.0.0.0:0", Listen, 1), vec![10]);
+        let web_a = owned(
+            socket("10.0.0.1:80", "10.0.0.9:5000", Established, 2),
+            vec![10],
+        );
+        let web_b = owned(
+            socket("10.0.0.1:80", "10.0.0.9:5001", Established, 3),
+            vec![10],
+        );
+        let backup = owned(
+            socket("10.0.0.1:4000", "10.0.0.8:22", Established, 4),
+            vec![30, 10],
+        );
+        let quiet = owned(
+            socket("10.0.0.1:4001", "10.0.0.8:22", Established, 5),
+            vec![20],
+        );
+        let orphan = owned(socket("10.0.0.1:80", "10.0.0.9:5002", FinWait2, 6), vec![]);
+
+        let mut previous = snapshot(
+            vec![
+                listener.clone(),
+                web_a.clone(),
+                backup.clone(),
+                quiet.clone(),
+            ],
+            vec![
+                (1, info(0, 0, 0, 0)),
+                (2, info(1000, 100, 10, 10)),
+                (4, info(5000, 0, 50, 50)),
+                (5, info(7, 7, 1, 1)),
+            ],
+        );
+        let mut current = snapshot(
+            vec![listener, web_a, web_b, backup, quiet, orphan],
+            vec![
+                (1, info(0, 0, 0, 0)),
+                (2, info(3000, 200, 30, 20)),
+                // Opened since the last poll, so counted from zero.
+                (3, info(500, 50, 5, 5)),
+                (4, info(9000, 0, 90, 50)),
+                (5, info(7, 7, 1, 1)),
+                (6, info(50, 0, 1, 1)),
+            ],
+        );
+        for snapshot in [&mut previous, &mut current] {
+            snapshot.users.insert(1000, Some(String::from("www")));
+            for (pid, command) in [(10, "nginx"), (20, "ssh"), (30, "rsync")] {
+                let process = Process {
+                    pid,
+                    command: String::from(command),
+                    cmdline: Vec::new(),
+                };
+                snapshot.processes.insert(pid, Some(process));
+            }
+        }
+
+        let row = |pid: Option<u32>, command: &str, sent, received, connections| {
+            let user = String::from("www");
+            let command = String::from(command);
+            let usage = Usage {
+                command,
+                user,
+                sent,
+                received,
+                connections,
+            };
+            (pid, usage)
+        };
+        assert_eq!(
+            usage(&previous, &current),
+            [
+                row(Some(30), "rsync", 4000, 0, 1),
+                row(Some(10), "nginx", 2500, 150, 2),
+                row(None, "-", 50, 0, 1),
+                row(Some(20), "ssh", 0, 0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn traffic_between_polls() {
+        let traffic = Traffic::between(&info(100, 50, 10, 5), &info(1600, 50, 12, 6));
+        assert_eq!(
+            traffic.to_string(),
+            "out +1500 bytes +2 packets, in +0 bytes +1 packets"
+        );
+        assert!(Traffic::between(&info(100, 50, 10, 5), &info(100, 50, 10, 5)).is_idle());
+
+        // The 32-bit segment counters wrap around.
+        let traffic = Traffic::between(&info(0, 0, u32::MAX - 1, u32::MAX), &info(0, 0, 3, 0));
+        assert_eq!((traffic.segs_out, traffic.segs_in), (5, 1));
+
+        // The byte counters only go down if the connection was replaced.
+        let traffic = Traffic::between(&info(9000, 9000, 90, 90), &info(300, 200, 3, 2));
+        assert_eq!(
+            traffic,
+            Traffic {
+                bytes_out: 300,
+                bytes_in: 200,
+                segs_out: 3,
+                segs_in: 2
+            }
+        );
+    }
+
+    #[test]
+    fn events_between_snapshots() {
+        use SocketState::*;
+
+        let listener = socket("127.0.0.1:8765", "0.0.0.0:0", Listen, 1);
+        let busy = socket("127.0.0.1:8765", "127.0.0.1:40000", Established, 2);
+        let idle = socket("127.0.0.1:8765", "127.0.0.1:40001", Established, 3);
+        let leaving = socket("127.0.0.1:8765", "127.0.0.1:40002", Established, 4);
+        let previous = snapshot(
+            vec![
+                listener.clone(),
+                busy.clone(),
+                idle.clone(),
+                leaving.clone(),
+            ],
+            vec![(2, info(10, 20, 1, 2)), (3, info(5, 5, 1, 1))],
+        );
+
+        let closing = Socket {
+            state: CloseWait,
+            ..idle.clone()
+        };
+        let arrived = socket("127.0.0.1:8765", "127.0.0.1:40003", SynRecv, 5);
+        let current = snapshot(
+            vec![listener, busy.clone(), closing.clone(), arrived.clone()],
+            vec![(2, info(110, 20, 3, 3)), (3, info(5, 5, 1, 1))],
+        );
+
+        assert_eq!(
+            events(&previous, &current),
+            [
+                Event::Data {
+                    socket: &busy,
+                    traffic: Traffic {
+                        bytes_out: 100,
+                        bytes_in: 0,
+                        segs_out: 2,
+                        segs_in: 1
+                    }
+                },
+                Event::State {
+                    socket: &closing,
+                    from: Established
+                },
+                Event::Opened(&arrived),
+                Event::Closed(&leaving),
+            ]
+        );
+        assert!(events(&current, &current).is_empty());
+    }
+}
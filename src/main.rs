@@ -5,13 +5,23 @@
 //! <li>catch</li>
 //! <li>trap</li>
 //! <li>fish</li>
+//! <li>scan</li>
 //! </ul>
 
 use structopt::StructOpt;
+use tacklebox::resolve::{Resolved, Resolver};
+use tacklebox::target::TargetSpec;
+use tacklebox::Error;
+
+use std::net::{IpAddr, SocketAddr};
+use std::process;
 
 mod cast;
 mod catch;
 mod fish;
+mod packet;
+mod scan;
+mod target;
 mod trap;
 
 /// Define command line options.
@@ -34,14 +44,126 @@ enum Opt {
         about = "Displays meta-information about specified port's usage"
     )]
     Fish(fish::Options),
+    #[structopt(
+        name = "scan",
+        about = "Connect-scans [HOST]:[PORTS] and reports which ports are open"
+    )]
+    Scan(scan::Options),
 }
 
 fn main() {
     let opt = Opt::from_args();
-    match opt {
+    let result = match opt {
         Opt::Catch(mut options) => catch::run(&mut options),
         Opt::Cast(mut options) => cast::run(&mut options),
         Opt::Trap(mut options) => trap::run(&mut options),
         Opt::Fish(mut options) => fish::run(&mut options),
+        Opt::Scan(mut options) => scan::run(&mut options),
+    };
+
+    if let Err(e) = result {
+        eprintln!("castline: {}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// Maps each kind of failure to its own exit status.
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::Address { .. }
+        | Error::Protocol(_)
+        | Error::SocketState(_)
+        | Error::TcpFlags(_)
+        | Error::IpOption { .. }
+        | Error::Duration(_)
+        | Error::Unsupported(_) => 2,
+        Error::Resolve { .. } => 3,
+        Error::Bind { .. } | Error::RawSocket(_) => 4,
+        Error::Connect { .. } | Error::NotConnected | Error::NoRemote => 5,
+        Error::Timeout(_) => 6,
+        Error::HeaderDecode { .. } | Error::Encode(_) => 7,
+        Error::Io(_) => 1,
+    }
+}
+
+/// Resolves `target`, printing any forward/reverse DNS warnings.
+pub(crate) fn resolve(resolver: &Resolver, target: &str) -> Result<SocketAddr, Error> {
+    Ok(warn(resolver.resolve(target)?).addr)
+}
+
+/// Resolves the host of `target`, printing any DNS warnings.
+pub(crate) fn resolve_host(resolver: &Resolver, target: &TargetSpec) -> Result<IpAddr, Error> {
+    Ok(warn(target.resolve(resolver)?).addr.ip())
+}
+
+/// Prints the DNS warnings of `resolved`.
+pub(crate) fn warn(resolved: Resolved) -> Resolved {
+    for warning in &resolved.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error as IoErr, ErrorKind};
+    use std::time::Duration;
+    use tacklebox::etherparse::{ReadError, WriteError};
+    use tacklebox::Protocol;
+
+    #[test]
+    fn every_error_has_its_exit_code() {
+        let io = || IoErr::from(ErrorKind::Other);
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let errors = [
+            (Error::Io(io()), 1),
+            (
+                Error::Address {
+                    addr: String::from("nowhere"),
+                    reason: String::from("expected host:ports"),
+                },
+                2,
+            ),
+            (Error::Protocol(String::from("sctp")), 2),
+            (Error::SocketState(String::from("dozing")), 2),
+            (Error::TcpFlags(String::from("syn,nope")), 2),
+            (
+                Error::IpOption {
+                    option: String::from("loose:"),
+                    reason: String::from("no hops"),
+                },
+                2,
+            ),
+            (Error::Duration(String::from("5 parsecs")), 2),
+            (Error::Unsupported("IP options over IPv6"), 2),
+            (
+                Error::Resolve {
+                    host: String::from("nowhere.test"),
+                    source: io(),
+                },
+                3,
+            ),
+            (Error::Bind { addr, source: io() }, 4),
+            (
+                Error::RawSocket(IoErr::from(ErrorKind::PermissionDenied)),
+                4,
+            ),
+            (Error::Connect { addr, source: io() }, 5),
+            (Error::NotConnected, 5),
+            (Error::NoRemote, 5),
+            (Error::Timeout(Duration::from_secs(1)), 6),
+            (
+                Error::HeaderDecode {
+                    protocol: Protocol::Tcp,
+                    source: ReadError::UnexpectedEndOfSlice(20),
+                },
+                7,
+            ),
+            (Error::Encode(WriteError::SliceTooSmall(20)), 7),
+        ];
+        for (error, code) in &errors {
+            assert_eq!(exit_code(error), *code, "{:?}", error);
+        }
     }
 }
@@ -0,0 +1,83 @@
+extern crate structopt;
+use structopt::StructOpt;
+use tacklebox::ipopt::{IpOption, IpOptions};
+use tacklebox::{PacketOptions, TcpFlags};
+
+/// Header fields for crafted packets, shared by `cast` and `trap`.
+///
+/// All of them apply with `--raw`; without it only `--ttl`, `--tos`,
+/// `--df`/`--no-df` and `--route` can be passed on to the kernel.
+#[derive(Debug, StructOpt)]
+pub struct PacketArgs {
+    #[structopt(long, help = "TTL, or hop limit for IPv6")]
+    pub ttl: Option<u8>,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "TOS byte (DSCP and ECN), or IPv6 traffic class"
+    )]
+    pub tos: u8,
+    #[structopt(long, help = "Set the Don't Fragment bit")]
+    pub df: bool,
+    #[structopt(long, conflicts_with = "df", help = "Clear the Don't Fragment bit")]
+    pub no_df: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Initial IPv4 identification field (--raw)"
+    )]
+    pub ip_id: u16,
+    #[structopt(
+        long,
+        default_value = "none",
+        help = "TCP flags to set, e.g. syn or psh,ack (--raw)"
+    )]
+    pub flags: TcpFlags,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Initial TCP sequence number (--raw)"
+    )]
+    pub seq: u32,
+    #[structopt(long, help = "TCP acknowledgment number; sets ACK (--raw)")]
+    pub ack: Option<u32>,
+    #[structopt(long, default_value = "65535", help = "TCP window size (--raw)")]
+    pub window: u16,
+    #[structopt(long, help = "TCP urgent pointer; sets URG (--raw)")]
+    pub urgent: Option<u16>,
+    #[structopt(
+        short = "g",
+        long,
+        number_of_values = 1,
+        help = "IPv4 option to send: loose:HOPS, strict:HOPS, record[:N], timestamp[:N] or \
+                timestamp-addr[:N]; may be repeated"
+    )]
+    pub route: Vec<IpOption>,
+}
+
+impl PacketArgs {
+    pub fn options(&self) -> PacketOptions {
+        let mut flags = self.flags;
+        flags.ack |= self.ack.is_some();
+        flags.urg |= self.urgent.is_some();
+
+        let dont_fragment = match (self.df, self.no_df) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        };
+
+        PacketOptions {
+            ttl: self.ttl,
+            tos: self.tos,
+            dont_fragment,
+            identification: self.ip_id,
+            flags,
+            sequence_number: self.seq,
+            acknowledgment_number: self.ack.unwrap_or(0),
+            window_size: self.window,
+            urgent_pointer: self.urgent.unwrap_or(0),
+            ip_options: IpOptions(self.route.clone()),
+        }
+    }
+}
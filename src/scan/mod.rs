@@ -0,0 +1,276 @@
+extern crate structopt;
+use structopt::StructOpt;
+use tacklebox::banner::{Banner, Grabber};
+use tacklebox::duration;
+use tacklebox::resolve::Resolver;
+use tacklebox::scan::{ConnectScan, PortReport, PortState, SynScan, UdpScan};
+use tacklebox::services::Services;
+use tacklebox::target::{PortRange, TargetSpec};
+use tacklebox::Error;
+
+use crate::target::OrderArgs;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+mod output;
+
+use output::{Scan, ScanKind};
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(
+        required = true,
+        help = "Hosts and ports to scan, e.g. host:22 or host:20-25,80,8000-8100"
+    )]
+    pub targets: Vec<TargetSpec>,
+    #[structopt(short, long, help = "Scan UDP ports instead of TCP")]
+    pub udp: bool,
+    #[structopt(
+        short,
+        long,
+        conflicts_with = "udp",
+        help = "Half-open SYN scan on a raw socket (needs root or CAP_NET_RAW)"
+    )]
+    pub syn: bool,
+    #[structopt(
+        short,
+        long,
+        default_value = "100",
+        help = "Most connections to have in flight at once"
+    )]
+    pub concurrency: usize,
+    #[structopt(
+        short,
+        long,
+        default_value = "1s",
+        parse(try_from_str = duration::parse),
+        help = "Time to wait for each connection or UDP reply (e.g. 250ms, 1.5s, 2m)"
+    )]
+    pub time: Duration,
+    #[structopt(
+        long,
+        default_value = "2",
+        help = "Times to resend to a silent port (UDP and SYN scans)"
+    )]
+    pub retries: u32,
+    #[structopt(long, help = "Most probes to send per second (UDP and SYN scans)")]
+    pub rate: Option<u32>,
+    #[structopt(
+        short = "N",
+        long = "numeric",
+        help = "Numeric-only addresses; skip DNS lookups"
+    )]
+    pub numeric: bool,
+    #[structopt(long, help = "Only list open ports")]
+    pub open: bool,
+    #[structopt(
+        long,
+        conflicts_with = "udp",
+        help = "Grab a banner from each open port and name the service"
+    )]
+    pub banner: bool,
+    #[structopt(long, help = "Write nmap XML results to a file, or - for stdout")]
+    pub xml: Option<PathBuf>,
+    #[structopt(long, help = "Write nmap grepable results to a file, or - for stdout")]
+    pub grepable: Option<PathBuf>,
+    #[structopt(long, help = "Write JSON results to a file, or - for stdout")]
+    pub json: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub order: OrderArgs,
+}
+
+pub fn run(options: &mut Options) -> Result<(), Error> {
+    let resolver = Resolver::new().numeric(options.numeric);
+    let mut targets: Vec<SocketAddr> = Vec::new();
+    let mut hosts: Vec<(IpAddr, Option<String>)> = Vec::new();
+    for target in &options.targets {
+        let resolved = crate::warn(target.resolve(&resolver)?);
+        let ip = resolved.addr.ip();
+        targets.extend(target.addrs(ip));
+        if !hosts.iter().any(|(host, _)| *host == ip) {
+            hosts.push((ip, resolved.name));
+        }
+    }
+    targets.sort();
+    targets.dedup();
+    options.order.arrange(&mut targets);
+
+    let start = SystemTime::now();
+    let now = Instant::now();
+    let mut reports = if options.udp {
+        UdpScan::new()
+            .concurrency(options.concurrency)
+            .timeout(options.time)
+            .retries(options.retries)
+            .rate(options.rate)
+            .run(&targets)?
+    } else if options.syn {
+        SynScan::new()
+            .timeout(options.time)
+            .retries(options.retries)
+            .rate(options.rate)
+            .run(&targets)?
+    } else {
+        ConnectScan::new()
+            .concurrency(options.concurrency)
+            .timeout(options.time)
+            .run(&targets)?
+    };
+    let scan_time = now.elapsed();
+
+    // Probes go out in whatever order was asked for, but read best
+    // sorted.
+    reports.sort_by_key(|report| report.addr);
+
+    let mut banners = HashMap::new();
+    if options.banner {
+        let open: Vec<SocketAddr> = reports
+            .iter()
+            .filter(|report| report.state == PortState::Open)
+            .map(|report| report.addr)
+            .collect();
+        let grabber = Grabber::new().timeout(options.time);
+        for (addr, banner) in open
+            .iter()
+            .zip(grabber.grab_all(&open, options.concurrency))
+        {
+            if let Some(banner) = banner {
+                banners.insert(*addr, banner);
+            }
+        }
+    }
+
+    let kind = if options.udp {
+        ScanKind::Udp
+    } else if options.syn {
+        ScanKind::Syn
+    } else {
+        ScanKind::Connect
+    };
+    let services = options
+        .targets
+        .iter()
+        .map(|target| target.ports.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let scan = Scan {
+        kind,
+        args: std::env::args().collect::<Vec<_>>().join(" "),
+        services: services.parse::<PortRange>()?,
+        start,
+        elapsed: scan_time,
+        hosts: &hosts,
+        reports: &reports,
+        banners: &banners,
+    };
+    let formats: [(&Option<PathBuf>, Format); 3] = [
+        (&options.xml, output::xml),
+        (&options.grepable, output::grepable),
+        (&options.json, output::json),
+    ];
+    let mut to_stdout = false;
+    for (path, format) in &formats {
+        if let Some(path) = path {
+            to_stdout |= write_output(path, &format(&scan))?;
+        }
+    }
+    // Keep stdout parseable if a format is going there.
+    if to_stdout {
+        return Ok(());
+    }
+
+    print_header(options.banner);
+    for report in &reports {
+        if !options.open || report.state == PortState::Open {
+            let label = Services::system().label(report.addr.port(), kind.protocol());
+            print_stats(report, &label, options.banner, banners.get(&report.addr));
+        }
+    }
+
+    let count = |state| {
+        reports
+            .iter()
+            .filter(|report| report.state == state)
+            .count()
+    };
+    print!(
+        "\n{} ports scanned in {} : {} open, {} closed, {} filtered",
+        reports.len(),
+        duration::format(scan_time),
+        count(PortState::Open),
+        count(PortState::Closed),
+        count(PortState::Filtered)
+    );
+    if options.udp {
+        print!(", {} open|filtered", count(PortState::OpenFiltered));
+    }
+    println!();
+    Ok(())
+}
+
+/// Renders a finished scan in one output format.
+type Format = fn(&Scan) -> String;
+
+/// Writes `contents` to `path`, or to stdout if `path` is `-`, and
+/// returns whether it went to stdout.
+fn write_output(path: &Path, contents: &str) -> Result<bool, Error> {
+    if path == Path::new("-") {
+        print!("{}", contents);
+        return Ok(true);
+    }
+    fs::write(path, contents)?;
+    Ok(false)
+}
+
+fn print_header(banner: bool) {
+    println!();
+
+    print!(
+        "{host:<width$}{port:<width$}{state:<width$}{latency:<width$}",
+        host = "HOST",
+        port = "PORT",
+        state = "STATE",
+        latency = "LATENCY",
+        width = 20
+    );
+    if banner {
+        print!("SERVICE");
+    }
+    println!();
+}
+
+fn print_stats(report: &PortReport, label: &str, show_banner: bool, banner: Option<&Banner>) {
+    let latency = match report.latency {
+        Some(latency) => duration::format(latency),
+        None => String::from("-"),
+    };
+
+    print!(
+        "{host:<width$}{port:<width$}{state:<width$}{latency:<width$}",
+        host = report.addr.ip().to_string(),
+        port = label,
+        state = report.state.to_string(),
+        latency = latency,
+        width = 20
+    );
+    if show_banner {
+        print!("{}", describe(banner));
+    }
+    println!();
+}
+
+/// The service a banner named, or its first line if none matched.
+pub(crate) fn describe(banner: Option<&Banner>) -> String {
+    match banner {
+        Some(Banner {
+            service: Some(service),
+            ..
+        }) => service.to_string(),
+        Some(banner) => format!("\"{}\"", banner.first_line()),
+        None => String::from("-"),
+    }
+}
@@ -0,0 +1,492 @@
+//! Scan results in the formats other tools read: nmap's XML and
+//! grepable output, and castline's own JSON.
+
+use tacklebox::banner::{self, Banner};
+use tacklebox::scan::{PortReport, PortState};
+use tacklebox::services::Services;
+use tacklebox::target::PortRange;
+use tacklebox::Protocol;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How the ports were probed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanKind {
+    Connect,
+    Syn,
+    Udp,
+}
+
+impl ScanKind {
+    /// The scan type as nmap names it.
+    fn name(self) -> &'static str {
+        match self {
+            ScanKind::Connect => "connect",
+            ScanKind::Syn => "syn",
+            ScanKind::Udp => "udp",
+        }
+    }
+
+    pub fn protocol(self) -> Protocol {
+        match self {
+            ScanKind::Udp => Protocol::Udp,
+            ScanKind::Connect | ScanKind::Syn => Protocol::Tcp,
+        }
+    }
+
+    /// Why a port is in `state`, in nmap's words.
+    fn reason(self, state: PortState) -> &'static str {
+        match (self, state) {
+            (ScanKind::Udp, PortState::Open) => "udp-response",
+            (ScanKind::Udp, PortState::Closed) => "port-unreach",
+            (ScanKind::Udp, PortState::Filtered) => "host-unreach",
+            (_, PortState::Open) => "syn-ack",
+            (ScanKind::Syn, PortState::Closed) => "reset",
+            (_, PortState::Closed) => "conn-refused",
+            (_, PortState::Filtered) | (_, PortState::OpenFiltered) => "no-response",
+        }
+    }
+}
+
+/// Everything a finished scan produced.
+pub struct Scan<'a> {
+    pub kind: ScanKind,
+    /// The command line, as typed.
+    pub args: String,
+    /// Every port scanned, on any host.
+    pub services: PortRange,
+    pub start: SystemTime,
+    pub elapsed: Duration,
+    /// Each host scanned and the name it was given by, in target order.
+    pub hosts: &'a [(IpAddr, Option<String>)],
+    /// Sorted by address.
+    pub reports: &'a [PortReport],
+    pub banners: &'a HashMap<SocketAddr, Banner>,
+}
+
+impl Scan<'_> {
+    /// The reports for `ip`.
+    fn ports(&self, ip: IpAddr) -> impl Iterator<Item = &PortReport> {
+        self.reports
+            .iter()
+            .filter(move |report| report.addr.ip() == ip)
+    }
+
+    /// The service on a port: the one its banner named, or else the
+    /// one `/etc/services` lists. Returns its name, version and how it
+    /// was found, in nmap's terms.
+    fn service(&self, report: &PortReport) -> Option<(&str, Option<&str>, &'static str)> {
+        let banner = self.banners.get(&report.addr);
+        match banner.and_then(|banner| banner.service.as_ref()) {
+            Some(service) => Some((service.name, service.version.as_deref(), "probed")),
+            None => Services::system()
+                .name(report.addr.port(), self.kind.protocol())
+                .map(|name| (name, None, "table")),
+        }
+    }
+
+    fn end(&self) -> SystemTime {
+        self.start + self.elapsed
+    }
+
+    fn summary(&self) -> String {
+        let hosts = self.hosts.len();
+        format!(
+            "{} IP address{} ({} host{} up) scanned in {:.2} seconds",
+            hosts,
+            if hosts == 1 { "" } else { "es" },
+            hosts,
+            if hosts == 1 { "" } else { "s" },
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// nmap's `-oX` format.
+pub fn xml(scan: &Scan) -> String {
+    let mut out = String::new();
+    let start = unix_secs(scan.start);
+    let end = unix_secs(scan.end());
+
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<!DOCTYPE nmaprun>\n");
+    let _ = writeln!(
+        out,
+        "<nmaprun scanner=\"castline\" args=\"{}\" start=\"{}\" startstr=\"{}\" \
+         version=\"{}\" xmloutputversion=\"1.05\">",
+        xml_escape(&scan.args),
+        start,
+        timestr(scan.start),
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(
+        out,
+        "<scaninfo type=\"{}\" protocol=\"{}\" numservices=\"{}\" services=\"{}\"/>",
+        scan.kind.name(),
+        scan.kind.protocol(),
+        scan.services.len(),
+        scan.services
+    );
+
+    for (ip, name) in scan.hosts {
+        let _ = writeln!(out, "<host starttime=\"{}\" endtime=\"{}\">", start, end);
+        out.push_str("<status state=\"up\" reason=\"user-set\" reason_ttl=\"0\"/>\n");
+        let _ = writeln!(
+            out,
+            "<address addr=\"{}\" addrtype=\"{}\"/>",
+            ip,
+            if ip.is_ipv4() { "ipv4" } else { "ipv6" }
+        );
+        match name {
+            Some(name) => {
+                let _ = writeln!(
+                    out,
+                    "<hostnames>\n<hostname name=\"{}\" type=\"user\"/>\n</hostnames>",
+                    xml_escape(name)
+                );
+            }
+            None => out.push_str("<hostnames>\n</hostnames>\n"),
+        }
+
+        out.push_str("<ports>");
+        for report in scan.ports(*ip) {
+            let _ = write!(
+                out,
+                "<port protocol=\"{}\" portid=\"{}\"><state state=\"{}\" reason=\"{}\" \
+                 reason_ttl=\"0\"/>",
+                scan.kind.protocol(),
+                report.addr.port(),
+                report.state,
+                scan.kind.reason(report.state)
+            );
+            if let Some((name, version, method)) = scan.service(report) {
+                let _ = write!(out, "<service name=\"{}\"", xml_escape(name));
+                if let Some(version) = version {
+                    let (product, version) = split_version(version);
+                    let _ = write!(out, " product=\"{}\"", xml_escape(product));
+                    if let Some(version) = version {
+                        let _ = write!(out, " version=\"{}\"", xml_escape(version));
+                    }
+                }
+                let conf = if method == "probed" { 10 } else { 3 };
+                let _ = write!(out, " method=\"{}\" conf=\"{}\"/>", method, conf);
+            }
+            out.push_str("</port>\n");
+        }
+        out.push_str("</ports>\n</host>\n");
+    }
+
+    let _ = writeln!(
+        out,
+        "<runstats><finished time=\"{}\" timestr=\"{}\" elapsed=\"{:.2}\" \
+         summary=\"Castline done at {}; {}\" exit=\"success\"/>\
+         <hosts up=\"{}\" down=\"0\" total=\"{}\"/>\n</runstats>",
+        end,
+        timestr(scan.end()),
+        scan.elapsed.as_secs_f64(),
+        timestr(scan.end()),
+        scan.summary(),
+        scan.hosts.len(),
+        scan.hosts.len()
+    );
+    out.push_str("</nmaprun>\n");
+    out
+}
+
+/// nmap's `-oG` format: a `Host:` line per host, with every port as
+/// `port/state/protocol/owner/service/rpc/version/`.
+pub fn grepable(scan: &Scan) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# Castline {} scan initiated {} as: {}",
+        env!("CARGO_PKG_VERSION"),
+        timestr(scan.start),
+        scan.args
+    );
+
+    for (ip, name) in scan.hosts {
+        let host = format!("Host: {} ({})", ip, name.as_deref().unwrap_or_default());
+        let _ = writeln!(out, "{}\tStatus: Up", host);
+
+        let ports: Vec<String> = scan
+            .ports(*ip)
+            .map(|report| {
+                let service = scan.service(report);
+                // The fields are slash-separated, so slashes in them
+                // are written as `|`, as nmap does.
+                let version = service
+                    .and_then(|(_, version, _)| version)
+                    .unwrap_or_default()
+                    .replace('/', "|");
+                format!(
+                    "{}/{}/{}//{}//{}/",
+                    report.addr.port(),
+                    report.state,
+                    scan.kind.protocol(),
+                    service.map(|(name, _, _)| name).unwrap_or_default(),
+                    version
+                )
+            })
+            .collect();
+        let _ = writeln!(out, "{}\tPorts: {}", host, ports.join(", "));
+    }
+
+    let _ = writeln!(
+        out,
+        "# Castline done at {} -- {}",
+        timestr(scan.end()),
+        scan.summary()
+    );
+    out
+}
+
+/// castline's own JSON: one object per host, with every port.
+pub fn json(scan: &Scan) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{{\"scanner\":\"castline\",\"version\":\"{}\",\"args\":{},\"start\":{},\
+         \"elapsed\":{:.6},\"type\":\"{}\",\"protocol\":\"{}\",\"hosts\":[",
+        env!("CARGO_PKG_VERSION"),
+        json_string(&scan.args),
+        unix_secs(scan.start),
+        scan.elapsed.as_secs_f64(),
+        scan.kind.name(),
+        scan.kind.protocol()
+    );
+
+    for (i, (ip, name)) in scan.hosts.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"address\":\"{}\",\"hostname\":{},\"ports\":[",
+            ip,
+            name.as_deref()
+                .map_or_else(|| String::from("null"), json_string)
+        );
+        for (j, report) in scan.ports(*ip).enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let latency = match report.latency {
+                Some(latency) => latency.as_micros().to_string(),
+                None => String::from("null"),
+            };
+            let _ = write!(
+                out,
+                "{{\"port\":{},\"state\":\"{}\",\"reason\":\"{}\",\"latency_us\":{}",
+                report.addr.port(),
+                report.state,
+                scan.kind.reason(report.state),
+                latency
+            );
+            if let Some((name, version, method)) = scan.service(report) {
+                let version = version.map_or_else(|| String::from("null"), json_string);
+                let _ = write!(
+                    out,
+                    ",\"service\":{{\"name\":{},\"version\":{},\"method\":\"{}\"}}",
+                    json_string(name),
+                    version,
+                    method
+                );
+            }
+            if let Some(banner) = scan.banners.get(&report.addr) {
+                let _ = write!(
+                    out,
+                    ",\"banner\":{}",
+                    json_string(&banner::escape(&banner.data))
+                );
+            }
+            out.push('}');
+        }
+        out.push_str("]}");
+    }
+    out.push_str("]}\n");
+    out
+}
+
+/// Splits `OpenSSH_9.6p1 Ubuntu-3` or `nginx/1.24.0` into the product
+/// and its version, the way nmap reports them.
+fn split_version(version: &str) -> (&str, Option<&str>) {
+    match version.split_once(['/', '_']) {
+        Some((product, version)) => (product, Some(version)),
+        None => (version, None),
+    }
+}
+
+/// Escapes `s` for XML text and attributes. Control characters other
+/// than tab and line breaks can't appear in XML 1.0 at all, not even as
+/// references, so they become U+FFFD.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{fffe}' | '\u{ffff}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            c if (c as u32) < 0x20 => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `time` the way nmap writes it, e.g. `Sun Oct 18 14:03:09 2026`, in
+/// UTC.
+fn timestr(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = unix_secs(time);
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // Days since the epoch to a civil date; see Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{} {} {:>2} {:02}:{:02}:{:02} {}",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        year
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tacklebox::banner::{Probe, Service};
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            xml_escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(xml_escape("a\tb\r\nc"), "a\tb\r\nc");
+        assert_eq!(
+            xml_escape("\x1b[0m\0\u{ffff}"),
+            "\u{fffd}[0m\u{fffd}\u{fffd}"
+        );
+        assert_eq!(
+            json_string("say \"hi\"\\\n\x1b"),
+            "\"say \\\"hi\\\"\\\\\\n\\u001b\""
+        );
+    }
+
+    #[test]
+    fn times_read_like_nmap() {
+        let at = |secs| timestr(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "Thu Jan  1 00:00:00 1970");
+        assert_eq!(at(951_782_400), "Tue Feb 29 00:00:00 2000");
+        assert_eq!(at(1_700_000_000), "Tue Nov 14 22:13:20 2023");
+    }
+
+    #[test]
+    fn grepable_lists_every_port() {
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let report = |port, state, latency| PortReport {
+            addr: SocketAddr::new(ip, port),
+            state,
+            latency,
+        };
+        let reports = [
+            report(22, PortState::Open, Some(Duration::from_millis(1))),
+            report(23, PortState::Closed, Some(Duration::from_millis(1))),
+            report(80, PortState::Open, Some(Duration::from_millis(2))),
+        ];
+        let mut banners = HashMap::new();
+        banners.insert(
+            SocketAddr::new(ip, 80),
+            Banner {
+                data: b"HTTP/1.1 200 OK\r\nServer: nginx/1.18.0\r\n\r\n".to_vec(),
+                probe: Probe::Http,
+                service: Some(Service {
+                    name: "http",
+                    version: Some(String::from("nginx/1.18.0")),
+                }),
+            },
+        );
+        let hosts = [(ip, Some(String::from("box.test")))];
+        let scan = Scan {
+            kind: ScanKind::Connect,
+            args: String::from("castline scan box.test:22-23,80"),
+            services: "22-23,80".parse().unwrap(),
+            start: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            elapsed: Duration::from_millis(1500),
+            hosts: &hosts,
+            reports: &reports,
+            banners: &banners,
+        };
+
+        let out = grepable(&scan);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("# Castline "));
+        assert!(lines[0].ends_with(
+            " scan initiated Tue Nov 14 22:13:20 2023 as: castline scan box.test:22-23,80"
+        ));
+        assert_eq!(lines[1], "Host: 192.0.2.7 (box.test)\tStatus: Up");
+        assert_eq!(
+            lines[2],
+            "Host: 192.0.2.7 (box.test)\tPorts: 22/open/tcp//ssh///, \
+             23/closed/tcp//telnet///, 80/open/tcp//http//nginx|1.18.0/"
+        );
+        assert_eq!(
+            lines[3],
+            "# Castline done at Tue Nov 14 22:13:21 2023 -- \
+             1 IP address (1 host up) scanned in 1.50 seconds"
+        );
+    }
+}
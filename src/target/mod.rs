@@ -0,0 +1,35 @@
+extern crate structopt;
+use rand::seq::SliceRandom;
+use structopt::StructOpt;
+use tacklebox::target::{self, PortRange};
+
+/// The order ports in a range are visited, shared by the subcommands
+/// that take a `host:PORTS` target.
+#[derive(Debug, StructOpt)]
+pub struct OrderArgs {
+    #[structopt(long, help = "Visit the ports in a random order")]
+    pub shuffle: bool,
+    #[structopt(
+        long,
+        help = "Seed for --shuffle, to repeat an order (implies --shuffle)"
+    )]
+    pub seed: Option<u64>,
+}
+
+impl OrderArgs {
+    /// The ports of `range`, ascending unless shuffling was asked for.
+    pub fn ports(&self, range: &PortRange) -> Vec<u16> {
+        if self.shuffle || self.seed.is_some() {
+            range.shuffled(&mut target::rng(self.seed))
+        } else {
+            range.iter().collect()
+        }
+    }
+
+    /// Shuffles `items` in place if shuffling was asked for.
+    pub fn arrange<T>(&self, items: &mut [T]) {
+        if self.shuffle || self.seed.is_some() {
+            items.shuffle(&mut target::rng(self.seed));
+        }
+    }
+}
@@ -1,49 +1,190 @@
 extern crate structopt;
 use structopt::StructOpt;
-use tacklebox::random_local;
-use tacklebox::tcp::TcpSession;
-use tacklebox::Sender;
+use tacklebox::duration;
+use tacklebox::resolve::Resolver;
+use tacklebox::scan;
+use tacklebox::services::Services;
+use tacklebox::target::TargetSpec;
+use tacklebox::{loopback, random_port};
+use tacklebox::{Error, Protocol, Session, SessionBuilder, WithSession};
 
-use std::convert::TryInto;
+use crate::packet::PacketArgs;
+use crate::target::OrderArgs;
+
+use std::io::ErrorKind as IoErrKind;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
-    #[structopt(short = "f", long = "from", help = "Socket to send datagrams from")]
+    #[structopt(short = "f", long = "from", help = "Host to send datagrams from")]
     pub local: Option<String>,
-    #[structopt(help = "Socket to send datagrams to")]
-    pub remote: String,
+    #[structopt(help = "Host and ports to send datagrams to, e.g. host:80 or host:8000-8010")]
+    pub remote: TargetSpec,
     #[structopt(short = "n", help = "Amount of datagrams to collect")]
     pub amount: Option<u16>,
     #[structopt(short, long, help = "Size of datagrams being sent")]
     pub packet_size: Option<u32>,
-    #[structopt(short, long, help = "Time to spend waiting for individual datagrams")]
-    pub time: Option<u32>,
+    #[structopt(
+        short,
+        long,
+        default_value = "5s",
+        parse(try_from_str = duration::parse),
+        help = "Time to spend waiting for individual datagrams (e.g. 250ms, 1.5s, 2m)"
+    )]
+    pub time: Duration,
+    #[structopt(
+        short,
+        long,
+        default_value = "100",
+        help = "Most ports to trap at once"
+    )]
+    pub concurrency: usize,
+    #[structopt(
+        short = "N",
+        long = "numeric",
+        help = "Numeric-only addresses; skip DNS lookups"
+    )]
+    pub numeric: bool,
+    #[structopt(
+        long,
+        help = "Send crafted IP packets on a raw socket (needs root or CAP_NET_RAW)"
+    )]
+    pub raw: bool,
+    #[structopt(flatten)]
+    pub order: OrderArgs,
+    #[structopt(flatten)]
+    pub packet: PacketArgs,
+}
+
+pub fn run(options: &mut Options) -> Result<(), Error> {
+    options.amount.get_or_insert(5);
+    options.packet_size.get_or_insert(5);
+
+    Protocol::Tcp.dispatch(&*options)
 }
 
-pub fn run(options: &mut Options) {
-    let amount = *options.amount.get_or_insert(5);
-    let size = *options.packet_size.get_or_insert(5);
-    let wait_time = *options.time.get_or_insert(5);
-
-    let local_writer = match &options.local {
-        Some(local) => local.clone(),
-        None => random_local(),
-    };
-
-    let mut writer = TcpSession::new(&local_writer[..]);
-    let connection = writer.connect_to(&options.remote[..]);
-    if connection.is_err() {
-        let connect_err = connection.unwrap_err();
-        println!("\nConnection not established : {}", connect_err);
+impl WithSession for &Options {
+    type Output = Result<(), Error>;
+
+    fn run<S: Session>(self) -> Self::Output {
+        let amount = self.amount.unwrap_or(5);
+        let size = self.packet_size.unwrap_or(5);
+
+        let resolver = Resolver::new().numeric(self.numeric);
+        let ip = crate::resolve_host(&resolver, &self.remote)?;
+        let from = match &self.local {
+            Some(local) => Some(crate::resolve(&resolver, local)?),
+            None => None,
+        };
+
+        // Ports are trapped `concurrency` at a time, and one that fails
+        // doesn't stop the rest.
+        let ports = self.order.ports(&self.remote.ports);
+        let targets: Vec<SocketAddr> = ports
+            .iter()
+            .map(|&port| SocketAddr::new(ip, port))
+            .collect();
+        let trap_all = |targets: &[SocketAddr]| {
+            scan::run(self.concurrency.max(1), targets, |remote| {
+                let local = match from {
+                    Some(from) if ports.len() == 1 => from,
+                    Some(from) => self.local_port(from.ip()),
+                    None => self.local_port(loopback(ip)),
+                };
+                Ok(trap::<S>(self, local, remote))
+            })
+        };
+        let mut results = trap_all(&targets)?;
+
+        // A port can be taken for a while by another port's connection
+        // as its ephemeral port. Try those again once everything else
+        // is done, for as long as that gets more of them through.
+        loop {
+            let busy: Vec<usize> = (0..targets.len())
+                .filter(|&i| in_use(&results[i]))
+                .collect();
+            if busy.is_empty() {
+                break;
+            }
+            let retargets: Vec<SocketAddr> = busy.iter().map(|&i| targets[i]).collect();
+            let retried = trap_all(&retargets)?;
+            let still_busy = retried.iter().filter(|result| in_use(result)).count();
+            for (i, result) in busy.into_iter().zip(retried) {
+                results[i] = result;
+            }
+            if still_busy == retargets.len() {
+                break;
+            }
+        }
+
+        // With a single port there is no table to put the error in.
+        if results.len() == 1 && results[0].is_err() {
+            return results.remove(0).map(|_| ());
+        }
+
+        print_header();
+        for (remote, received) in targets.into_iter().zip(results) {
+            let received = match received {
+                Ok(received) => received.to_string(),
+                Err(e) => format!("error: {}", e),
+            };
+            print_stats(remote, amount, size, &received);
+        }
+        Ok(())
     }
+}
 
-    let mut local_reader = String::from("127.0.0.1:");
-    local_reader.push_str(&writer.remote_port.unwrap().to_string()[..]);
-    let mut reader = TcpSession::new(&local_reader[..]);
+impl Options {
+    /// Where to send one port's packets from, on `ip`. A plain session
+    /// only listens there, so port 0 lets the kernel pick one that no
+    /// other port's reader wants; a raw one binds no port but has to
+    /// write one into its packets.
+    fn local_port(&self, ip: IpAddr) -> SocketAddr {
+        if self.raw {
+            random_port(ip)
+        } else {
+            SocketAddr::new(ip, 0)
+        }
+    }
+}
 
-    let payloads = vec![vec![1; size.try_into().unwrap()]; amount.into()];
+/// Whether a port couldn't be trapped because its port was taken.
+fn in_use(result: &Result<usize, Error>) -> bool {
+    match result {
+        Err(Error::Bind { source, .. }) => source.kind() == IoErrKind::AddrInUse,
+        _ => false,
+    }
+}
+
+/// Sends `amount` packets from `local` to `remote` and counts how many
+/// arrive.
+fn trap<S: Session>(
+    options: &Options,
+    local: SocketAddr,
+    remote: SocketAddr,
+) -> Result<usize, Error> {
+    let amount = options.amount.unwrap_or(5);
+    let size = options.packet_size.unwrap_or(5);
+    let wait_time = options.time;
+
+    let mut reader: S = SessionBuilder::new()
+        .local(SocketAddr::new(loopback(remote.ip()), remote.port()))
+        .raw(options.raw)
+        .build()?;
+    let mut writer: S = SessionBuilder::new()
+        .local(local)
+        .remote(remote)
+        // Its connection's port may be one a later port's reader needs.
+        .reuse_address(true)
+        .packet_options(options.packet.options())
+        .raw(options.raw)
+        .build()?;
+    reader.accept(wait_time)?;
+
+    let payloads = vec![vec![1; size as usize]; amount.into()];
     for payload in &payloads {
-        writer.send(&payload).expect("send failed!");
+        writer.send(payload)?;
     }
 
     let mut received = 0;
@@ -52,16 +193,16 @@ pub fn run(options: &mut Options) {
             received += 1;
         }
     }
-
-    print_header();
-    print_stats(amount, size, received);
+    Ok(received)
 }
 
 fn print_header() {
     println!();
 
     println!(
-        "{amount:<width$}{size:<width$}{received:<width$}",
+        "{target:<width$}{port:<width$}{amount:<width$}{size:<width$}{received:<width$}",
+        target = "TARGET",
+        port = "PORT",
         amount = "AMOUNT SENT",
         size = "PACKET SIZE",
         received = "PACKETS RECEIVED",
@@ -69,9 +210,11 @@ fn print_header() {
     );
 }
 
-fn print_stats(amount: u16, size: u32, received: usize) {
+fn print_stats(target: SocketAddr, amount: u16, size: u32, received: &str) {
     println!(
-        "{amount:<width$}{size:<width$}{received:<width$}",
+        "{target:<width$}{port:<width$}{amount:<width$}{size:<width$}{received:<width$}",
+        target = target.to_string(),
+        port = Services::system().label(target.port(), Protocol::Tcp),
         amount = amount,
         size = size,
         received = received,
@@ -0,0 +1,11 @@
+//! Tokio versions of the sessions, enabled with the `async` feature.
+//!
+//! They send and receive the same `TcpDatagram`s and `UdpDatagram`s
+//! as the blocking sessions, and are built from a `SessionBuilder`.
+//! Raw mode, GSO and GRO are only available on the blocking sessions.
+
+mod tcp;
+mod udp;
+
+pub use tcp::AsyncTcpSession;
+pub use udp::AsyncUdpSession;
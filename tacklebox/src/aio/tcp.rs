@@ -0,0 +1,103 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::session::SocketOptions;
+use crate::tcp::TcpDatagram;
+use crate::Error;
+
+pub struct AsyncTcpSession {
+    pub local: SocketAddr,
+    pub remote: Option<SocketAddr>,
+    listener: TcpListener,
+    socket: Option<TcpStream>,
+    options: SocketOptions,
+}
+
+impl AsyncTcpSession {
+    /// Listens on `local` with default socket options; see `SessionBuilder`.
+    /// With port 0, `local` ends up holding the port the kernel picked.
+    pub async fn new(local: SocketAddr) -> Result<AsyncTcpSession, Error> {
+        AsyncTcpSession::with_options(local, SocketOptions::default())
+    }
+
+    pub(crate) fn with_options(
+        local: SocketAddr,
+        options: SocketOptions,
+    ) -> Result<AsyncTcpSession, Error> {
+        if options.raw {
+            return Err(Error::Unsupported("raw sockets in async sessions"));
+        }
+        let listener = options.tcp_listener(local)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(AsyncTcpSession {
+            local: listener.local_addr()?,
+            remote: None,
+            listener: TcpListener::from_std(listener)?,
+            socket: None,
+            options,
+        })
+    }
+
+    pub async fn connect_to(&mut self, remote: SocketAddr) -> Result<(), Error> {
+        self.remote = Some(remote);
+        let connect = TcpStream::connect(remote);
+        let connected = match self.options.connect_timeout {
+            Some(wait_time) => timeout(wait_time, connect)
+                .await
+                .map_err(|_| Error::Timeout(wait_time))?,
+            None => connect.await,
+        };
+        let socket = connected.map_err(|source| Error::Connect {
+            addr: remote,
+            source,
+        })?;
+        self.configure(&socket)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    /// Waits up to `wait_time` for a peer to connect, returning how
+    /// long that took.
+    pub async fn accept(&mut self, wait_time: Duration) -> Result<Duration, Error> {
+        let time = Instant::now();
+        let (socket, addr) = timeout(wait_time, self.listener.accept())
+            .await
+            .map_err(|_| Error::Timeout(wait_time))??;
+        self.configure(&socket)?;
+        self.remote = Some(addr);
+        self.socket = Some(socket);
+        Ok(time.elapsed())
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> Result<usize, Error> {
+        let socket = self.socket.as_mut().ok_or(Error::NotConnected)?;
+        socket.write_all(payload).await?;
+        Ok(payload.len())
+    }
+
+    /// Waits up to `wait_time` for the next datagram, returning it
+    /// along with how long the wait took.
+    pub async fn receive(&mut self, wait_time: Duration) -> Result<(TcpDatagram, Duration), Error> {
+        let socket = self.socket.as_mut().ok_or(Error::NotConnected)?;
+
+        let mut buf = vec![0u8; 65536];
+
+        let now = Instant::now();
+        let bytes = timeout(wait_time, socket.read(&mut buf))
+            .await
+            .map_err(|_| Error::Timeout(wait_time))??;
+        let read_time = now.elapsed();
+        Ok((TcpDatagram::plain(&buf[..bytes]), read_time))
+    }
+
+    fn configure(&self, socket: &TcpStream) -> Result<(), Error> {
+        socket.set_nodelay(self.options.nodelay)?;
+        self.options.packet.apply(socket, socket.local_addr()?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::session::SocketOptions;
+use crate::udp::UdpDatagram;
+use crate::Error;
+
+pub struct AsyncUdpSession {
+    pub local: SocketAddr,
+    pub remote: Option<SocketAddr>,
+    socket: UdpSocket,
+}
+
+impl AsyncUdpSession {
+    /// Binds `local` with default socket options; see `SessionBuilder`.
+    /// With port 0, `local` ends up holding the port the kernel picked.
+    pub async fn new(local: SocketAddr) -> Result<AsyncUdpSession, Error> {
+        AsyncUdpSession::with_options(local, SocketOptions::default())
+    }
+
+    pub(crate) fn with_options(
+        local: SocketAddr,
+        options: SocketOptions,
+    ) -> Result<AsyncUdpSession, Error> {
+        if options.raw {
+            return Err(Error::Unsupported("raw sockets in async sessions"));
+        }
+        // Reads and writes are single datagrams; nothing would split a
+        // coalesced read or batch a send.
+        if options.gro || options.gso {
+            return Err(Error::Unsupported("UDP offload in async sessions"));
+        }
+        let socket = options.udp_socket(local)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(AsyncUdpSession {
+            local: socket.local_addr()?,
+            remote: None,
+            socket: UdpSocket::from_std(socket)?,
+        })
+    }
+
+    pub fn set_remote(&mut self, remote: SocketAddr) {
+        self.remote = Some(remote);
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> Result<usize, Error> {
+        let remote = self.remote.ok_or(Error::NoRemote)?;
+        let bytes = self.socket.send_to(payload, remote).await?;
+        Ok(bytes)
+    }
+
+    /// Waits up to `wait_time` for the next datagram, returning it
+    /// along with how long the wait took.
+    pub async fn receive(&mut self, wait_time: Duration) -> Result<(UdpDatagram, Duration), Error> {
+        let mut buf = vec![0u8; 65536];
+
+        let now = Instant::now();
+        let bytes = timeout(wait_time, self.socket.recv(&mut buf))
+            .await
+            .map_err(|_| Error::Timeout(wait_time))??;
+        let read_time = now.elapsed();
+        Ok((UdpDatagram::plain(&buf[..bytes]), read_time))
+    }
+}
@@ -0,0 +1,413 @@
+//! Banner grabbing: connect, wait for or provoke the first bytes a
+//! service sends, and name the service from them.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind as IoErrKind;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::{scan, Error};
+
+/// Most bytes of a reply to keep.
+const BANNER_LIMIT: usize = 512;
+
+/// How long to keep reading once a reply has started.
+const TRAILING_READ: Duration = Duration::from_millis(100);
+
+/// What to send once connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    /// Nothing; wait for services that speak first, like SSH and SMTP.
+    Null,
+    /// `GET / HTTP/1.0`.
+    Http,
+    /// A Redis `PING`.
+    Redis,
+    /// A PostgreSQL `SSLRequest`, which any version answers.
+    Postgres,
+}
+
+impl Probe {
+    /// Every probe, in the order they are tried by default.
+    pub const ALL: [Probe; 4] = [Probe::Null, Probe::Http, Probe::Redis, Probe::Postgres];
+
+    pub fn payload(self) -> &'static [u8] {
+        match self {
+            Probe::Null => b"",
+            Probe::Http => b"GET / HTTP/1.0\r\n\r\n",
+            Probe::Redis => b"PING\r\n",
+            Probe::Postgres => &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f],
+        }
+    }
+
+    /// The probe to try first on a well-known port, if it isn't `Null`.
+    fn hint(port: u16) -> Option<Probe> {
+        match port {
+            80 | 591 | 3000 | 5000 | 8000 | 8008 | 8080 | 8888 => Some(Probe::Http),
+            6379 => Some(Probe::Redis),
+            5432 => Some(Probe::Postgres),
+            _ => None,
+        }
+    }
+}
+
+/// A service named from its banner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub name: &'static str,
+    pub version: Option<String>,
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// The first bytes a service sent back, and what they matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Banner {
+    pub data: Vec<u8>,
+    /// What was sent to get them.
+    pub probe: Probe,
+    pub service: Option<Service>,
+}
+
+impl Banner {
+    /// The first line of the banner, with anything unprintable escaped.
+    pub fn first_line(&self) -> String {
+        let line = self.data.split(|&b| b == b'\n').next().unwrap_or_default();
+        escape(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+}
+
+/// Escapes `bytes` the way `b"..."` literals are written.
+pub fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect()
+}
+
+/// A signature: the service it names, and a test returning the version
+/// if the banner matches (`Some(None)` when no version shows).
+struct Signature {
+    name: &'static str,
+    matches: fn(&[u8]) -> Option<Option<String>>,
+}
+
+/// Tried in order; the first match names the service.
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        name: "ssh",
+        matches: |data| {
+            let line = text_line(data.strip_prefix(b"SSH-")?);
+            // `SSH-2.0-OpenSSH_9.6p1 Ubuntu-3`: drop the protocol version.
+            Some(
+                line.split_once('-')
+                    .map(|(_, software)| software.to_string()),
+            )
+        },
+    },
+    Signature {
+        name: "ftp",
+        matches: |data| {
+            let line = text_line(data.strip_prefix(b"220")?);
+            line.to_ascii_uppercase()
+                .contains("FTP")
+                .then(|| version_in(&line))
+        },
+    },
+    Signature {
+        name: "smtp",
+        matches: |data| {
+            let line = text_line(data.strip_prefix(b"220")?);
+            line.to_ascii_uppercase()
+                .contains("SMTP")
+                .then(|| version_in(&line))
+        },
+    },
+    Signature {
+        name: "pop3",
+        matches: |data| {
+            data.strip_prefix(b"+OK")
+                .map(|rest| version_in(&text_line(rest)))
+        },
+    },
+    Signature {
+        name: "imap",
+        matches: |data| {
+            data.strip_prefix(b"* OK")
+                .map(|rest| version_in(&text_line(rest)))
+        },
+    },
+    Signature {
+        name: "http",
+        matches: |data| {
+            data.strip_prefix(b"HTTP/")?;
+            let text = String::from_utf8_lossy(data);
+            let server = text.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("server")
+                    .then(|| value.trim().to_string())
+            });
+            Some(server)
+        },
+    },
+    Signature {
+        name: "redis",
+        matches: |data| {
+            let replies: [&[u8]; 4] = [b"+PONG", b"-ERR ", b"-NOAUTH", b"-DENIED"];
+            replies
+                .iter()
+                .any(|reply| data.starts_with(reply))
+                .then_some(None)
+        },
+    },
+    Signature {
+        name: "postgresql",
+        matches: |data| {
+            // An `SSLRequest` is answered with a lone `S` or `N`; other
+            // probes get an `ErrorResponse` with severity `FATAL`.
+            let error = data.starts_with(b"E") && contains(data, b"SFATAL");
+            (data == b"S" || data == b"N" || error).then_some(None)
+        },
+    },
+    Signature {
+        name: "mysql",
+        matches: |data| {
+            // A 4-byte packet header, then protocol version 10 and a
+            // NUL-terminated server version.
+            let handshake = data.get(4..)?.strip_prefix(&[10])?;
+            let end = handshake.iter().position(|&b| b == 0)?;
+            Some(Some(
+                String::from_utf8_lossy(&handshake[..end]).into_owned(),
+            ))
+        },
+    },
+];
+
+/// The first line of `data` as trimmed text.
+fn text_line(data: &[u8]) -> String {
+    let line = data.split(|&b| b == b'\n').next().unwrap_or_default();
+    String::from_utf8_lossy(line).trim().to_string()
+}
+
+/// The first word that looks like `name/1.2` or `name_1.2`, which is
+/// how most greetings carry the server's version.
+fn version_in(line: &str) -> Option<String> {
+    line.split_whitespace()
+        .find(|word| word.contains(['/', '_']) && word.contains(|c: char| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Names the service that sent `data`, if any signature matches.
+pub fn identify(data: &[u8]) -> Option<Service> {
+    SIGNATURES.iter().find_map(|signature| {
+        (signature.matches)(data).map(|version| Service {
+            name: signature.name,
+            version,
+        })
+    })
+}
+
+/// Connects to services and identifies them from what they send back.
+#[derive(Debug, Clone)]
+pub struct Grabber {
+    timeout: Duration,
+    payload: Option<Vec<u8>>,
+}
+
+impl Default for Grabber {
+    fn default() -> Self {
+        Grabber {
+            timeout: Duration::from_secs(2),
+            payload: None,
+        }
+    }
+}
+
+impl Grabber {
+    pub fn new() -> Grabber {
+        Grabber::default()
+    }
+
+    /// How long to wait to connect and for each reply (default 2s).
+    pub fn timeout(mut self, timeout: Duration) -> Grabber {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send `payload` instead of trying the built-in probes.
+    pub fn payload(mut self, payload: Option<&[u8]>) -> Grabber {
+        self.payload = payload.map(<[u8]>::to_vec);
+        self
+    }
+
+    /// Connects to `addr` and returns the first reply, trying each
+    /// probe on a fresh connection until one gets an answer. The
+    /// probe a well-known port suggests goes first.
+    ///
+    /// Returns `Error::Timeout` if nothing ever answers.
+    pub fn grab(&self, addr: SocketAddr) -> Result<Banner, Error> {
+        if let Some(payload) = &self.payload {
+            let data = self.exchange(addr, payload)?;
+            return match data.is_empty() {
+                true => Err(Error::Timeout(self.timeout)),
+                false => Ok(Banner {
+                    service: identify(&data),
+                    probe: Probe::Null,
+                    data,
+                }),
+            };
+        }
+
+        let hint = Probe::hint(addr.port());
+        let probes = hint.into_iter().chain(
+            Probe::ALL
+                .iter()
+                .copied()
+                .filter(|&probe| Some(probe) != hint),
+        );
+        for probe in probes {
+            let data = self.exchange(addr, probe.payload())?;
+            if !data.is_empty() {
+                return Ok(Banner {
+                    service: identify(&data),
+                    probe,
+                    data,
+                });
+            }
+        }
+        Err(Error::Timeout(self.timeout))
+    }
+
+    /// Grabs a banner from every address in `targets`, up to
+    /// `concurrency` at a time. Addresses that refuse or never answer
+    /// get `None`.
+    pub fn grab_all(&self, targets: &[SocketAddr], concurrency: usize) -> Vec<Option<Banner>> {
+        let banners = scan::run(concurrency.max(1), targets, |addr| Ok(self.grab(addr).ok()));
+        banners.unwrap_or_default()
+    }
+
+    /// Sends `payload` on a new connection and reads what comes back,
+    /// which is empty if nothing does within the timeout.
+    fn exchange(&self, addr: SocketAddr, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)
+            .map_err(|source| Error::Connect { addr, source })?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        if !payload.is_empty() {
+            stream.write_all(payload)?;
+        }
+
+        let mut data = vec![0u8; BANNER_LIMIT];
+        let mut len = 0;
+        let started = Instant::now();
+        let mut wait = self.timeout;
+        while len < BANNER_LIMIT {
+            let left = wait.saturating_sub(started.elapsed());
+            if left.is_zero() {
+                break;
+            }
+            stream.set_read_timeout(Some(left))?;
+            match stream.read(&mut data[len..]) {
+                Ok(0) => break,
+                Ok(read) => {
+                    len += read;
+                    // Take whatever follows quickly, then stop.
+                    wait = started.elapsed() + TRAILING_READ;
+                }
+                Err(ref e) if e.kind() == IoErrKind::WouldBlock => break,
+                Err(ref e) if e.kind() == IoErrKind::TimedOut => break,
+                Err(ref e) if e.kind() == IoErrKind::Interrupted => {}
+                // Services that dislike the probe tend to hang up.
+                Err(ref e) if e.kind() == IoErrKind::ConnectionReset => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The service name and version a banner should be identified as.
+    type Expected = Option<(&'static str, Option<&'static str>)>;
+
+    #[test]
+    fn identifies_real_banners() {
+        let banners: &[(&[u8], Expected)] = &[
+            (
+                b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6\r\n",
+                Some(("ssh", Some("OpenSSH_8.9p1 Ubuntu-3ubuntu0.6"))),
+            ),
+            (
+                b"SSH-2.0-OpenSSH_8.9\r\n",
+                Some(("ssh", Some("OpenSSH_8.9"))),
+            ),
+            (
+                b"220 mail.example.com ESMTP Postfix (Ubuntu)\r\n",
+                Some(("smtp", None)),
+            ),
+            (
+                b"220 mx.example.com ESMTP Exim_4.96 Mon, 02 Oct 2023\r\n",
+                Some(("smtp", Some("Exim_4.96"))),
+            ),
+            (b"220 (vsFTPd 3.0.5)\r\n", Some(("ftp", None))),
+            (
+                b"HTTP/1.1 400 Bad Request\r\nServer: nginx/1.18.0 (Ubuntu)\r\n\r\n",
+                Some(("http", Some("nginx/1.18.0 (Ubuntu)"))),
+            ),
+            (
+                b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n",
+                Some(("http", None)),
+            ),
+            (b"+OK Dovecot (Ubuntu) ready.\r\n", Some(("pop3", None))),
+            (
+                b"-NOAUTH Authentication required.\r\n",
+                Some(("redis", None)),
+            ),
+            (b"N", Some(("postgresql", None))),
+            (
+                b"\x4a\x00\x00\x00\x0a8.0.36\x00\x08\x00\x00\x00",
+                Some(("mysql", Some("8.0.36"))),
+            ),
+            (b"\x00\x01\xfe\xffgarbage\r\n", None),
+            (b"hello there\n", None),
+            (b"", None),
+        ];
+
+        for (data, expected) in banners {
+            let found = identify(data);
+            let found = found
+                .as_ref()
+                .map(|service| (service.name, service.version.as_deref()));
+            assert_eq!(found, *expected, "banner {}", escape(data));
+        }
+    }
+
+    #[test]
+    fn first_line_is_escaped() {
+        let banner = Banner {
+            data: b"220 caf\xc3\xa9 \x1b[0m\r\nsecond line\r\n".to_vec(),
+            probe: Probe::Null,
+            service: None,
+        };
+        assert_eq!(banner.first_line(), "220 caf\\xc3\\xa9 \\x1b[0m");
+    }
+}
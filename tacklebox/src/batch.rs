@@ -0,0 +1,349 @@
+extern crate libc;
+use std::io::Error as IoErr;
+use std::io::ErrorKind as IoErrKind;
+use std::net::{SocketAddr, UdpSocket};
+
+#[cfg(target_os = "linux")]
+use std::{mem, os::unix::io::AsRawFd, ptr};
+
+use crate::udp::UdpDatagram;
+
+/// Most datagrams the kernel accepts in one `UDP_SEGMENT` send.
+#[cfg(target_os = "linux")]
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Largest payload a single UDP send can carry.
+#[cfg(target_os = "linux")]
+const MAX_GSO_BYTES: usize = 65507;
+
+/// Room for one `UDP_GRO` or `UDP_SEGMENT` control message.
+#[cfg(target_os = "linux")]
+type ControlBuffer = [u64; 4];
+
+/// A datagram's place in the pool: which buffer, and where in it.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    buffer: usize,
+    start: usize,
+    end: usize,
+}
+
+/// A set of receive buffers that is filled by one batched read and
+/// reused by the next.
+///
+/// With GRO a single buffer can hold several coalesced datagrams, so a
+/// batch may yield more datagrams than the pool has buffers.
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    #[cfg(target_os = "linux")]
+    control: Vec<ControlBuffer>,
+    segments: Vec<Segment>,
+}
+
+impl BufferPool {
+    /// `count` buffers of `size` bytes each.
+    pub fn new(count: usize, size: usize) -> BufferPool {
+        BufferPool {
+            buffers: vec![vec![0u8; size]; count.max(1)],
+            #[cfg(target_os = "linux")]
+            control: vec![[0; 4]; count.max(1)],
+            segments: Vec::with_capacity(count.max(1)),
+        }
+    }
+
+    /// How many reads one batch can hold.
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// How many datagrams the last batch read.
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.segments.get(index).map(|s| self.slice(*s))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments.iter().map(move |s| self.slice(*s))
+    }
+
+    fn slice(&self, segment: Segment) -> &[u8] {
+        &self.buffers[segment.buffer][segment.start..segment.end]
+    }
+
+    /// Records a read of `len` bytes into `buffer`, split into
+    /// `segment_size` datagrams if the kernel coalesced them.
+    fn push(&mut self, buffer: usize, len: usize, segment_size: Option<usize>) {
+        let step = match segment_size {
+            Some(size) if size > 0 => size,
+            _ => len.max(1),
+        };
+        let mut start = 0;
+        loop {
+            let end = (start + step).min(len);
+            self.segments.push(Segment { buffer, start, end });
+            start = end;
+            if start >= len {
+                break;
+            }
+        }
+    }
+
+    /// Owned copies of the datagrams read by the last batch. Callers
+    /// that only need the bytes should use `iter`, which copies nothing.
+    pub fn datagrams(&self) -> impl Iterator<Item = UdpDatagram> + '_ {
+        self.iter().map(UdpDatagram::plain)
+    }
+}
+
+/// Reads as many datagrams as are queued on `socket`, up to the
+/// pool's capacity, without blocking.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_batch(socket: &UdpSocket, pool: &mut BufferPool) -> Result<usize, IoErr> {
+    let mut iovecs: Vec<libc::iovec> = pool
+        .buffers
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(pool.control.iter_mut())
+        .map(|(iov, control)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+            msg
+        })
+        .collect();
+
+    pool.segments.clear();
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(IoErr::last_os_error());
+    }
+
+    for (buffer, msg) in msgs[..received as usize].iter().enumerate() {
+        let segment_size = gro_segment_size(&msg.msg_hdr);
+        pool.push(buffer, msg.msg_len as usize, segment_size);
+    }
+    Ok(pool.len())
+}
+
+/// The size of the datagrams the kernel coalesced into this read, if
+/// it coalesced any.
+#[cfg(target_os = "linux")]
+fn gro_segment_size(msg: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_UDP && header.cmsg_type == libc::UDP_GRO {
+            let size = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+            return Some(size as usize);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    None
+}
+
+/// Asks the kernel to coalesce datagrams from the same flow into
+/// larger reads.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_gro(socket: &UdpSocket) -> Result<(), IoErr> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoErr::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn recv_batch(socket: &UdpSocket, pool: &mut BufferPool) -> Result<usize, IoErr> {
+    socket.set_nonblocking(true)?;
+    pool.segments.clear();
+    for buffer in 0..pool.buffers.len() {
+        match socket.recv(&mut pool.buffers[buffer]) {
+            Ok(bytes) => pool.push(buffer, bytes, None),
+            Err(ref e) if e.kind() == IoErrKind::WouldBlock && buffer > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pool.len())
+}
+
+/// Sends every packet in `packets` to `remote`, returning how many
+/// went out before the socket buffer filled up.
+#[cfg(target_os = "linux")]
+pub(crate) fn send_batch(
+    socket: &UdpSocket,
+    packets: &[Vec<u8>],
+    remote: SocketAddr,
+) -> Result<usize, IoErr> {
+    let remote = socket2::SockAddr::from(remote);
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iov| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = remote.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = remote.len();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let ret = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = IoErr::last_os_error();
+            match e.kind() {
+                IoErrKind::Interrupted => continue,
+                IoErrKind::WouldBlock if sent > 0 => break,
+                _ => return Err(e),
+            }
+        }
+        sent += ret as usize;
+    }
+    Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn send_batch(
+    socket: &UdpSocket,
+    packets: &[Vec<u8>],
+    remote: SocketAddr,
+) -> Result<usize, IoErr> {
+    for packet in packets {
+        socket.send_to(packet, remote)?;
+    }
+    Ok(packets.len())
+}
+
+/// Sends `packets` to `remote` with `UDP_SEGMENT`, handing the kernel
+/// runs of equally sized packets to split into datagrams itself.
+/// Returns how many packets went out.
+#[cfg(target_os = "linux")]
+pub(crate) fn send_gso(
+    socket: &UdpSocket,
+    packets: &[Vec<u8>],
+    remote: SocketAddr,
+) -> Result<usize, IoErr> {
+    let remote = socket2::SockAddr::from(remote);
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+
+    // Every segment but the last in a send has to be the same size.
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < packets.len() {
+        let size = packets[start].len();
+        let mut bytes = size;
+        let mut end = start + 1;
+        while end < packets.len()
+            && end - start < MAX_GSO_SEGMENTS
+            && bytes + packets[end].len() <= MAX_GSO_BYTES
+            && packets[end].len() <= size
+        {
+            bytes += packets[end].len();
+            end += 1;
+            if packets[end - 1].len() < size {
+                break;
+            }
+        }
+        runs.push((start, end, size));
+        start = end;
+    }
+
+    let mut control = vec![[0u64; 4]; runs.len()];
+    let mut msgs: Vec<libc::mmsghdr> = runs
+        .iter()
+        .zip(control.iter_mut())
+        .map(|(&(start, end, size), control)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = remote.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = remote.len();
+            msg.msg_hdr.msg_iov = iovecs[start..].as_mut_ptr();
+            msg.msg_hdr.msg_iovlen = (end - start) as _;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen =
+                unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size as u16);
+            }
+            msg
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let ret = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = IoErr::last_os_error();
+            match e.kind() {
+                IoErrKind::Interrupted => continue,
+                IoErrKind::WouldBlock if sent > 0 => break,
+                _ => return Err(e),
+            }
+        }
+        sent += ret as usize;
+    }
+    Ok(runs[..sent].iter().map(|(start, end, _)| end - start).sum())
+}
@@ -0,0 +1,263 @@
+//! Per-connection TCP metrics from the kernel's `sock_diag` netlink
+//! interface, the same source `ss -ti` reads.
+
+extern crate libc;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::Error;
+
+#[cfg(target_os = "linux")]
+use socket2::{Domain, Protocol, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::io::Error as IoErr;
+#[cfg(target_os = "linux")]
+use std::mem::MaybeUninit;
+
+/// Netlink message types and `inet_diag` attributes, from
+/// `linux/netlink.h`, `linux/sock_diag.h` and `linux/inet_diag.h`.
+#[cfg(target_os = "linux")]
+const NLMSG_ERROR: u16 = 2;
+#[cfg(target_os = "linux")]
+const NLMSG_DONE: u16 = 3;
+#[cfg(target_os = "linux")]
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+#[cfg(target_os = "linux")]
+const INET_DIAG_INFO: u16 = 2;
+#[cfg(target_os = "linux")]
+const INET_DIAG_CONG: u16 = 4;
+
+/// Sizes of `nlmsghdr`, `inet_diag_req_v2` and `inet_diag_msg`.
+#[cfg(target_os = "linux")]
+const HEADER_LEN: usize = 16;
+#[cfg(target_os = "linux")]
+const REQUEST_LEN: usize = 56;
+#[cfg(target_os = "linux")]
+const MESSAGE_LEN: usize = 72;
+
+/// What the kernel knows about a TCP connection's health.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time.
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// Congestion window, in segments.
+    pub cwnd: u32,
+    /// Segments retransmitted over the connection's life.
+    pub retransmits: u32,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub segs_out: u32,
+    pub segs_in: u32,
+    /// Bytes per second, if the connection is being paced.
+    pub pacing_rate: Option<u64>,
+    /// The congestion control algorithm, e.g. `cubic` or `bbr`.
+    pub congestion: Option<String>,
+}
+
+impl TcpInfo {
+    /// Reads the fields we use out of a `struct tcp_info`. Older
+    /// kernels send a shorter struct; fields it stops short of are 0.
+    fn parse(info: &[u8]) -> TcpInfo {
+        let u32_at = |offset: usize| {
+            info.get(offset..offset + 4)
+                .map_or(0, |b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let u64_at = |offset: usize| {
+            info.get(offset..offset + 8).map_or(0, |b| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(b);
+                u64::from_ne_bytes(bytes)
+            })
+        };
+
+        let pacing_rate = u64_at(104);
+        TcpInfo {
+            rtt: Duration::from_micros(u64::from(u32_at(68))),
+            rtt_var: Duration::from_micros(u64::from(u32_at(72))),
+            cwnd: u32_at(80),
+            retransmits: u32_at(100),
+            bytes_acked: u64_at(120),
+            bytes_received: u64_at(128),
+            segs_out: u32_at(136),
+            segs_in: u32_at(140),
+            // `~0` means pacing is off.
+            pacing_rate: (pacing_rate != 0 && pacing_rate != u64::MAX).then_some(pacing_rate),
+            congestion: None,
+        }
+    }
+}
+
+/// `TCP_INFO` for every TCP socket the kernel will describe, over IPv4
+/// and IPv6, keyed by socket inode to match `procnet::Socket::inode`.
+/// Sockets in `TIME_WAIT` have no inode and are left out.
+#[cfg(target_os = "linux")]
+pub fn tcp_info() -> Result<HashMap<u64, TcpInfo>, Error> {
+    let socket = Socket::new(
+        Domain::from(libc::AF_NETLINK),
+        Type::RAW,
+        Some(Protocol::from(libc::NETLINK_SOCK_DIAG)),
+    )?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut infos = HashMap::new();
+    for family in &[libc::AF_INET, libc::AF_INET6] {
+        dump(&socket, *family as u8, &mut infos)?;
+    }
+    Ok(infos)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_info() -> Result<HashMap<u64, TcpInfo>, Error> {
+    Err(Error::Unsupported("sock_diag"))
+}
+
+/// Asks for every TCP socket of `family` and collects the replies.
+#[cfg(target_os = "linux")]
+fn dump(socket: &Socket, family: u8, infos: &mut HashMap<u64, TcpInfo>) -> Result<(), Error> {
+    let mut request = Vec::with_capacity(HEADER_LEN + REQUEST_LEN);
+    // nlmsghdr: length, type, flags, sequence number, port id.
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+    request.extend_from_slice(&((HEADER_LEN + REQUEST_LEN) as u32).to_ne_bytes());
+    request.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    request.extend_from_slice(&flags.to_ne_bytes());
+    request.extend_from_slice(&1u32.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    // inet_diag_req_v2: family, protocol, extensions wanted, padding,
+    // states wanted, then an all-zero socket id to match any socket.
+    let extensions = 1 << (INET_DIAG_INFO - 1) | 1 << (INET_DIAG_CONG - 1);
+    request.extend_from_slice(&[family, libc::IPPROTO_TCP as u8, extensions, 0]);
+    request.extend_from_slice(&u32::MAX.to_ne_bytes());
+    request.resize(HEADER_LEN + REQUEST_LEN, 0);
+    socket.send(&request)?;
+
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        // socket2 reads into uninitialised memory; ours is already
+        // initialised, which is always safe to treat as such.
+        let uninit = buf.as_mut_slice() as *mut [u8] as *mut [MaybeUninit<u8>];
+        let read = socket.recv(unsafe { &mut *uninit })?;
+
+        let mut offset = 0;
+        while offset + HEADER_LEN <= read {
+            let header = &buf[offset..read];
+            let len = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let kind = u16::from_ne_bytes([header[4], header[5]]);
+            if len < HEADER_LEN || len > header.len() {
+                break;
+            }
+            match kind {
+                NLMSG_DONE => return Ok(()),
+                NLMSG_ERROR => {
+                    let errno = header
+                        .get(HEADER_LEN..HEADER_LEN + 4)
+                        .map_or(0, |b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]]));
+                    if errno != 0 {
+                        return Err(IoErr::from_raw_os_error(-errno).into());
+                    }
+                }
+                SOCK_DIAG_BY_FAMILY => {
+                    if let Some((inode, info)) = parse_message(&header[HEADER_LEN..len]) {
+                        infos.insert(inode, info);
+                    }
+                }
+                _ => {}
+            }
+            offset += align(len);
+        }
+    }
+}
+
+/// Parses an `inet_diag_msg` and the attributes after it, returning
+/// the socket's inode and metrics if it came with `INET_DIAG_INFO`.
+#[cfg(target_os = "linux")]
+fn parse_message(message: &[u8]) -> Option<(u64, TcpInfo)> {
+    let inode = message.get(68..MESSAGE_LEN)?;
+    let inode = u32::from_ne_bytes([inode[0], inode[1], inode[2], inode[3]]);
+
+    let mut info = None;
+    let mut congestion = None;
+    let mut attrs = &message[MESSAGE_LEN..];
+    // rtattr: length (including itself), type, then the payload.
+    while attrs.len() >= 4 {
+        let len = usize::from(u16::from_ne_bytes([attrs[0], attrs[1]]));
+        let kind = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        let payload = attrs.get(4..len)?;
+        match kind {
+            INET_DIAG_INFO => info = Some(TcpInfo::parse(payload)),
+            INET_DIAG_CONG => {
+                let name = payload.split(|&b| b == 0).next().unwrap_or_default();
+                congestion = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            _ => {}
+        }
+        attrs = attrs.get(align(len)..).unwrap_or_default();
+    }
+
+    let mut info = info?;
+    info.congestion = congestion;
+    Some((u64::from(inode), info))
+}
+
+/// Netlink messages and attributes are padded out to 4 bytes.
+#[cfg(target_os = "linux")]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Instant;
+
+    fn inode(socket: &impl AsRawFd) -> u64 {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::fstat(socket.as_raw_fd(), &mut stat) }, 0);
+        stat.st_ino as u64
+    }
+
+    #[test]
+    fn loopback_connection_has_metrics() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.write_all(&[7; 1000]).unwrap();
+        server.read_exact(&mut [0; 1000]).unwrap();
+        server.write_all(&[9; 500]).unwrap();
+        client.read_exact(&mut [0; 500]).unwrap();
+
+        // The last ACKs may still be on their way.
+        let started = Instant::now();
+        let (client_info, server_info) = loop {
+            let infos = tcp_info().unwrap();
+            let client_info = infos.get(&inode(&client)).cloned();
+            let server_info = infos.get(&inode(&server)).cloned();
+            match (client_info, server_info) {
+                (Some(c), Some(s)) if c.bytes_acked >= 1000 && s.bytes_acked >= 500 => {
+                    break (c, s)
+                }
+                _ if started.elapsed() > Duration::from_secs(2) => {
+                    panic!("no metrics for the connection among {}", infos.len())
+                }
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // The side that connected also counts its SYN as acknowledged.
+        assert!((1000..=1001).contains(&client_info.bytes_acked));
+        assert_eq!(server_info.bytes_acked, 500);
+        assert_eq!(client_info.bytes_received, 500);
+        assert_eq!(server_info.bytes_received, 1000);
+        assert!(client_info.segs_out > 0 && client_info.segs_in > 0);
+        assert!(client_info.cwnd > 0);
+        assert!(client_info
+            .congestion
+            .as_deref()
+            .is_some_and(|name| !name.is_empty()));
+    }
+}
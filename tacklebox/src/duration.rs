@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use crate::Error;
+
+/// Parses a human duration such as `250ms`, `1.5s`, `2m` or `1h`.
+///
+/// A bare number is taken as seconds, so `--time 30` means what it
+/// always has.
+pub fn parse(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::Duration(s.to_string());
+
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| invalid())?;
+
+    let scale = match unit.trim() {
+        "ns" => 1e-9,
+        "us" | "µs" => 1e-6,
+        "ms" => 1e-3,
+        "" | "s" | "sec" | "secs" => 1.0,
+        "m" | "min" | "mins" => 60.0,
+        "h" | "hr" | "hrs" => 3600.0,
+        _ => return Err(invalid()),
+    };
+
+    Duration::try_from_secs_f64(value * scale).map_err(|_| invalid())
+}
+
+/// Formats `duration` with microsecond precision in the largest
+/// unit that keeps it readable: `850µs`, `12.345ms`, `1.500000s`.
+pub fn format(duration: Duration) -> String {
+    let micros = duration.as_micros();
+    if micros < 1_000 {
+        format!("{}µs", micros)
+    } else if micros < 1_000_000 {
+        format!("{:.3}ms", micros as f64 / 1e3)
+    } else {
+        format!("{:.6}s", micros as f64 / 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse("10µs").unwrap(), Duration::from_micros(10));
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        // 2^64 seconds, the first value `Duration` can't hold.
+        for bad in &[
+            "18446744073709551616",
+            "1e30",
+            "99999999999999999999h",
+            "-1s",
+            "5 fortnights",
+        ] {
+            assert!(
+                matches!(parse(bad), Err(Error::Duration(_))),
+                "{} should not parse",
+                bad
+            );
+        }
+        assert!(parse("10000000000000000000").is_ok());
+    }
+}
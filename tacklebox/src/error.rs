@@ -0,0 +1,58 @@
+use etherparse::{ReadError, WriteError};
+use thiserror::Error;
+
+use std::io::Error as IoErr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::Protocol;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid address `{addr}`: {reason}")]
+    Address { addr: String, reason: String },
+    #[error("could not resolve `{host}`: {source}")]
+    Resolve { host: String, source: IoErr },
+    #[error("could not bind to {addr}: {source}")]
+    Bind { addr: SocketAddr, source: IoErr },
+    #[error("could not open raw socket (needs root or CAP_NET_RAW): {0}")]
+    RawSocket(IoErr),
+    #[error("could not connect to {addr}: {source}")]
+    Connect { addr: SocketAddr, source: IoErr },
+    #[error("timed out after {}", crate::duration::format(*.0))]
+    Timeout(Duration),
+    #[error("invalid duration `{0}`")]
+    Duration(String),
+    #[error("could not decode {protocol} header: {source}")]
+    HeaderDecode {
+        protocol: Protocol,
+        source: ReadError,
+    },
+    #[error("could not build packet: {0}")]
+    Encode(#[from] WriteError),
+    #[error("no remote address set")]
+    NoRemote,
+    #[error("not connected")]
+    NotConnected,
+    #[error("invalid protocol `{0}`")]
+    Protocol(String),
+    #[error("invalid socket state `{0}`; expected e.g. `listen` or `time-wait`")]
+    SocketState(String),
+    #[error("invalid IP option `{option}`: {reason}")]
+    IpOption { option: String, reason: String },
+    #[error("invalid TCP flags `{0}`; expected e.g. `syn,ack` or `none`")]
+    TcpFlags(String),
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    Io(#[from] IoErr),
+}
+
+impl Error {
+    pub(crate) fn address(addr: &str, reason: impl ToString) -> Error {
+        Error::Address {
+            addr: addr.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+extern crate libc;
+use std::io::Error as IoErr;
+use std::io::ErrorKind as IoErrKind;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::{mem, os::unix::io::AsRawFd, ptr};
+
+/// Asks the kernel to queue every ICMP error for the socket's peer,
+/// not just the ones it counts as fatal. Without this a connected UDP
+/// socket hears about port unreachable but not host unreachable.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable(socket: &UdpSocket, local: SocketAddr) -> Result<(), IoErr> {
+    let (level, name) = match local {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_RECVERR),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVERR),
+    };
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoErr::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable(_socket: &UdpSocket, _local: SocketAddr) -> Result<(), IoErr> {
+    Ok(())
+}
+
+/// Takes the oldest ICMP error off the socket's error queue, if there
+/// is one, as the errno the kernel mapped it to: `ECONNREFUSED` for
+/// port unreachable, `EHOSTUNREACH` for host unreachable or
+/// administratively filtered, and so on.
+///
+/// Reading the queue also clears the pending error, so the socket
+/// stops polling as ready.
+#[cfg(target_os = "linux")]
+pub(crate) fn take(socket: &UdpSocket) -> Result<Option<IoErr>, IoErr> {
+    let mut data = [0u8; 512];
+    let mut control = [0u64; 64];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) } < 0 {
+        let e = IoErr::last_os_error();
+        return match e.kind() {
+            IoErrKind::WouldBlock => Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let recverr = (header.cmsg_level == libc::IPPROTO_IP
+            && header.cmsg_type == libc::IP_RECVERR)
+            || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+        if recverr {
+            let err = unsafe {
+                ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+            };
+            return Ok(Some(IoErr::from_raw_os_error(err.ee_errno as i32)));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn take(_socket: &UdpSocket) -> Result<Option<IoErr>, IoErr> {
+    Ok(None)
+}
+
+/// Whether `e` is how a connected socket reports an ICMP error from
+/// its peer, rather than a problem with the socket itself.
+pub(crate) fn is_unreachable(e: &IoErr) -> bool {
+    matches!(
+        e.kind(),
+        IoErrKind::ConnectionRefused
+            | IoErrKind::HostUnreachable
+            | IoErrKind::NetworkUnreachable
+            | IoErrKind::PermissionDenied
+    ) || e.raw_os_error() == Some(libc::ENOPROTOOPT)
+}
@@ -0,0 +1,51 @@
+//! Ctrl-C handling for loops that run until the user stops them.
+
+extern crate libc;
+use std::io::Error as IoErr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+/// How often `sleep` checks whether it was interrupted.
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // Only catch the first one, so a second Ctrl-C still kills a loop
+    // that is stuck.
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+}
+
+/// Catches the next SIGINT instead of letting it end the process;
+/// `interrupted` then returns true.
+pub fn catch() -> Result<(), Error> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGINT, handler) } == libc::SIG_ERR {
+        return Err(IoErr::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Whether SIGINT has arrived since `catch`.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration`, waking early if interrupted. Returns false
+/// if it was.
+pub fn sleep(duration: Duration) -> bool {
+    let started = Instant::now();
+    while !interrupted() {
+        let left = duration.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(SLEEP_SLICE));
+    }
+    false
+}
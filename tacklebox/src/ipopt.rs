@@ -0,0 +1,638 @@
+//! IPv4 options: loose and strict source routes, record route and
+//! timestamps (RFC 791).
+//!
+//! Options can be set on a session through `PacketOptions::ip_options`
+//! and decoded from the headers of packets read in raw mode.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use crate::Error;
+
+const END_OF_LIST: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const RECORD_ROUTE: u8 = 7;
+const TIMESTAMP: u8 = 68;
+const LOOSE_SOURCE_ROUTE: u8 = 131;
+const STRICT_SOURCE_ROUTE: u8 = 137;
+
+/// The most option bytes an IPv4 header has room for.
+const MAX_LEN: usize = 40;
+
+/// Timestamp option flag for entries that carry an address.
+const TS_WITH_ADDRESS: u8 = 1;
+
+/// A single IPv4 option.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpOption {
+    /// Hops to pass through on the way, in order, before the
+    /// destination (LSRR). Other routers may be used in between.
+    LooseSourceRoute(Vec<Ipv4Addr>),
+    /// Like `LooseSourceRoute`, but each hop must be directly reachable
+    /// from the last (SSRR).
+    StrictSourceRoute(Vec<Ipv4Addr>),
+    /// Room for `slots` routers to record their address, and the
+    /// addresses recorded so far. Zero slots means as many as fit.
+    RecordRoute {
+        slots: usize,
+        recorded: Vec<Ipv4Addr>,
+    },
+    /// Room for `slots` routers to record a timestamp in milliseconds
+    /// since midnight UT, with their address if `addresses` is set.
+    /// Zero slots means as many as fit.
+    Timestamp {
+        addresses: bool,
+        slots: usize,
+        overflow: u8,
+        recorded: Vec<(Option<Ipv4Addr>, u32)>,
+    },
+    /// Any option this module doesn't know.
+    Other { kind: u8, data: Vec<u8> },
+}
+
+impl IpOption {
+    fn kind(&self) -> u8 {
+        match self {
+            IpOption::LooseSourceRoute(_) => LOOSE_SOURCE_ROUTE,
+            IpOption::StrictSourceRoute(_) => STRICT_SOURCE_ROUTE,
+            IpOption::RecordRoute { .. } => RECORD_ROUTE,
+            IpOption::Timestamp { .. } => TIMESTAMP,
+            IpOption::Other { kind, .. } => *kind,
+        }
+    }
+
+    fn hops(&self) -> Option<&[Ipv4Addr]> {
+        match self {
+            IpOption::LooseSourceRoute(hops) | IpOption::StrictSourceRoute(hops) => Some(hops),
+            _ => None,
+        }
+    }
+
+    /// Bytes per slot, for options that size themselves.
+    fn slot_len(&self) -> Option<usize> {
+        match self {
+            IpOption::RecordRoute { .. } => Some(4),
+            IpOption::Timestamp { addresses, .. } => Some(if *addresses { 8 } else { 4 }),
+            _ => None,
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match self {
+            IpOption::Timestamp { .. } => 4,
+            _ => 3,
+        }
+    }
+}
+
+impl FromStr for IpOption {
+    type Err = Error;
+
+    /// Parses `loose:HOP,...`, `strict:HOP,...`, `record[:SLOTS]`,
+    /// `timestamp[:SLOTS]` or `timestamp-addr[:SLOTS]`. Hops must be
+    /// IPv4 addresses.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Error::IpOption {
+            option: s.to_string(),
+            reason: reason.to_string(),
+        };
+        let (name, arg) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let slots = || match arg {
+            Some(n) => n.parse().map_err(|_| invalid("expected a number of slots")),
+            None => Ok(0),
+        };
+        let hops = || -> Result<Vec<Ipv4Addr>, Error> {
+            let arg = arg.ok_or_else(|| invalid("expected a list of hops"))?;
+            arg.split(',')
+                .map(|hop| {
+                    hop.trim()
+                        .parse()
+                        .map_err(|_| invalid("hops must be IPv4 addresses"))
+                })
+                .collect()
+        };
+
+        match name {
+            "loose" | "lsrr" => Ok(IpOption::LooseSourceRoute(hops()?)),
+            "strict" | "ssrr" => Ok(IpOption::StrictSourceRoute(hops()?)),
+            "record" | "rr" => Ok(IpOption::RecordRoute {
+                slots: slots()?,
+                recorded: Vec::new(),
+            }),
+            "timestamp" | "ts" | "timestamp-addr" | "tsaddr" => Ok(IpOption::Timestamp {
+                addresses: name == "timestamp-addr" || name == "tsaddr",
+                slots: slots()?,
+                overflow: 0,
+                recorded: Vec::new(),
+            }),
+            _ => Err(invalid(
+                "expected loose:HOPS, strict:HOPS, record, timestamp or timestamp-addr",
+            )),
+        }
+    }
+}
+
+impl Display for IpOption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fn list<T: Display>(f: &mut Formatter<'_>, items: impl Iterator<Item = T>) -> fmt::Result {
+            for (i, item) in items.enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", item)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            IpOption::LooseSourceRoute(hops) => {
+                write!(f, "LSRR ")?;
+                list(f, hops.iter())
+            }
+            IpOption::StrictSourceRoute(hops) => {
+                write!(f, "SSRR ")?;
+                list(f, hops.iter())
+            }
+            IpOption::RecordRoute { slots, recorded } => {
+                write!(f, "RR ({}/{}) ", recorded.len(), slots)?;
+                list(f, recorded.iter())
+            }
+            IpOption::Timestamp {
+                slots,
+                overflow,
+                recorded,
+                ..
+            } => {
+                write!(
+                    f,
+                    "TS ({}/{}, {} overflowed) ",
+                    recorded.len(),
+                    slots,
+                    overflow
+                )?;
+                list(
+                    f,
+                    recorded.iter().map(|(addr, ms)| match addr {
+                        Some(addr) => format!("{}@{}ms", addr, ms),
+                        None => format!("{}ms", ms),
+                    }),
+                )
+            }
+            IpOption::Other { kind, data } => write!(f, "option {} ({} bytes)", kind, data.len()),
+        }
+    }
+}
+
+/// The options of one IPv4 header.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IpOptions(pub Vec<IpOption>);
+
+impl IpOptions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The source route, if there is one.
+    fn source_route(&self) -> Option<&[Ipv4Addr]> {
+        self.0.iter().find_map(IpOption::hops)
+    }
+
+    /// Where a packet for `destination` is first sent: the first hop
+    /// of the source route, or `destination` itself.
+    pub fn first_hop(&self, destination: Ipv4Addr) -> Ipv4Addr {
+        match self.source_route() {
+            Some(hops) if !hops.is_empty() => hops[0],
+            _ => destination,
+        }
+    }
+
+    /// The options as they go on the wire in a packet headed for
+    /// `destination`. A source route's first hop goes in the header's
+    /// destination field instead (see `first_hop`), and `destination`
+    /// takes the last slot.
+    pub fn encode(&self, destination: Ipv4Addr) -> Result<Vec<u8>, Error> {
+        self.encode_with(|hops| {
+            hops.iter()
+                .skip(1)
+                .copied()
+                .chain(Some(destination))
+                .collect()
+        })
+    }
+
+    /// The options as Linux takes them for `IP_OPTIONS`: every hop of a
+    /// source route and nothing more. The kernel moves the first hop
+    /// into the header and writes the destination into the last slot.
+    pub fn encode_for_socket(&self) -> Result<Vec<u8>, Error> {
+        self.encode_with(|hops| hops.to_vec())
+    }
+
+    fn encode_with(&self, route: impl Fn(&[Ipv4Addr]) -> Vec<Ipv4Addr>) -> Result<Vec<u8>, Error> {
+        let too_long = || Error::IpOption {
+            option: self.to_string(),
+            reason: format!("options take more than {} bytes", MAX_LEN),
+        };
+
+        // Options that size themselves share whatever room the rest
+        // leave.
+        let mut fixed_len = 0;
+        let mut auto = 0;
+        for option in &self.0 {
+            fixed_len += match option {
+                IpOption::LooseSourceRoute(hops) | IpOption::StrictSourceRoute(hops) => {
+                    3 + 4 * route(hops).len()
+                }
+                IpOption::RecordRoute { slots: 0, .. } | IpOption::Timestamp { slots: 0, .. } => {
+                    auto += 1;
+                    0
+                }
+                IpOption::RecordRoute { slots, .. } | IpOption::Timestamp { slots, .. } => {
+                    option.header_len() + slots * option.slot_len().unwrap_or(4)
+                }
+                IpOption::Other { data, .. } => 2 + data.len(),
+            };
+        }
+        if fixed_len > MAX_LEN {
+            return Err(too_long());
+        }
+        let share = (MAX_LEN - fixed_len) / auto.max(1);
+
+        let mut bytes = Vec::with_capacity(MAX_LEN);
+        for option in &self.0 {
+            match option {
+                IpOption::LooseSourceRoute(hops) | IpOption::StrictSourceRoute(hops) => {
+                    if hops.is_empty() {
+                        return Err(Error::IpOption {
+                            option: option.to_string(),
+                            reason: "a source route needs at least one hop".to_string(),
+                        });
+                    }
+                    let route = route(hops);
+                    bytes.extend_from_slice(&[option.kind(), (3 + 4 * route.len()) as u8, 4]);
+                    for hop in route {
+                        bytes.extend_from_slice(&hop.octets());
+                    }
+                }
+                IpOption::RecordRoute { slots, .. } | IpOption::Timestamp { slots, .. } => {
+                    let header_len = option.header_len();
+                    let slot_len = option.slot_len().unwrap_or(4);
+                    let slots = match slots {
+                        0 => share.saturating_sub(header_len) / slot_len,
+                        n => *n,
+                    };
+                    if slots == 0 {
+                        return Err(too_long());
+                    }
+                    let len = header_len + slots * slot_len;
+                    // The pointer is one-based and starts at the first slot.
+                    bytes.extend_from_slice(&[option.kind(), len as u8, header_len as u8 + 1]);
+                    if let IpOption::Timestamp { addresses, .. } = option {
+                        bytes.push(if *addresses { TS_WITH_ADDRESS } else { 0 });
+                    }
+                    bytes.resize(bytes.len() + slots * slot_len, 0);
+                }
+                IpOption::Other { kind, data } => {
+                    bytes.extend_from_slice(&[*kind, (2 + data.len()) as u8]);
+                    bytes.extend_from_slice(data);
+                }
+            }
+        }
+
+        // The header length counts 32-bit words.
+        while bytes.len() % 4 != 0 {
+            bytes.push(END_OF_LIST);
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes the options of a received IPv4 header.
+    pub fn decode(header: &[u8]) -> Result<IpOptions, Error> {
+        let malformed = |reason: &str| Error::IpOption {
+            option: format!("{:?}", header),
+            reason: reason.to_string(),
+        };
+
+        let mut bytes = header;
+        let mut options = Vec::new();
+        while let Some(&kind) = bytes.first() {
+            match kind {
+                END_OF_LIST => break,
+                NO_OPERATION => {
+                    bytes = &bytes[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *bytes.get(1).ok_or_else(|| malformed("option cut short"))? as usize;
+            if len < 2 || len > bytes.len() {
+                return Err(malformed("bad option length"));
+            }
+            let (option, rest) = bytes.split_at(len);
+            bytes = rest;
+
+            let addresses = |data: &[u8]| -> Vec<Ipv4Addr> {
+                data.chunks_exact(4)
+                    .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+                    .collect()
+            };
+            // The pointer is one-based and counts the type and length.
+            let pointer = |option: &[u8], header_len: usize| -> usize {
+                option
+                    .get(2)
+                    .map_or(header_len, |&p| (p as usize).saturating_sub(1))
+                    .clamp(header_len, option.len())
+            };
+
+            options.push(match kind {
+                LOOSE_SOURCE_ROUTE | STRICT_SOURCE_ROUTE if len >= 3 => {
+                    let hops = addresses(&option[3..]);
+                    if kind == LOOSE_SOURCE_ROUTE {
+                        IpOption::LooseSourceRoute(hops)
+                    } else {
+                        IpOption::StrictSourceRoute(hops)
+                    }
+                }
+                RECORD_ROUTE if len >= 3 => IpOption::RecordRoute {
+                    slots: (len - 3) / 4,
+                    recorded: addresses(&option[3..pointer(option, 3)]),
+                },
+                TIMESTAMP if len >= 4 => {
+                    let addresses = option[3] & 0x0f != 0;
+                    let slot_len = if addresses { 8 } else { 4 };
+                    let recorded = option[4..pointer(option, 4)]
+                        .chunks_exact(slot_len)
+                        .map(|entry| {
+                            let (addr, time) = entry.split_at(slot_len - 4);
+                            let addr = match addr {
+                                [a, b, c, d] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+                                _ => None,
+                            };
+                            (
+                                addr,
+                                u32::from_be_bytes([time[0], time[1], time[2], time[3]]),
+                            )
+                        })
+                        .collect();
+                    IpOption::Timestamp {
+                        addresses,
+                        slots: (len - 4) / slot_len,
+                        overflow: option[3] >> 4,
+                        recorded,
+                    }
+                }
+                _ => IpOption::Other {
+                    kind,
+                    data: option[2..].to_vec(),
+                },
+            });
+        }
+        Ok(IpOptions(options))
+    }
+}
+
+impl Display for IpOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, option) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", option)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::Ipv4Header;
+
+    fn options(specs: &[&str]) -> IpOptions {
+        IpOptions(specs.iter().map(|spec| spec.parse().unwrap()).collect())
+    }
+
+    fn ip(addr: &str) -> Ipv4Addr {
+        addr.parse().unwrap()
+    }
+
+    /// The options of a whole IPv4 header, given in hex.
+    fn captured(hex: &str) -> IpOptions {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        let (header, _rest) = Ipv4Header::read_from_slice(&bytes).unwrap();
+        IpOptions::decode(header.options()).unwrap()
+    }
+
+    #[test]
+    fn source_routes_round_trip() {
+        let destination = ip("10.0.0.2");
+        let loose = options(&["loose:10.0.0.7,10.0.0.8"]);
+        assert_eq!(loose.first_hop(destination), ip("10.0.0.7"));
+        let bytes = loose.encode(destination).unwrap();
+        assert_eq!(bytes, [131, 11, 4, 10, 0, 0, 8, 10, 0, 0, 2, END_OF_LIST]);
+        assert_eq!(
+            IpOptions::decode(&bytes).unwrap(),
+            IpOptions(vec![IpOption::LooseSourceRoute(vec![
+                ip("10.0.0.8"),
+                destination
+            ])])
+        );
+
+        let strict = options(&["strict:10.0.0.7"]);
+        let bytes = strict.encode(destination).unwrap();
+        assert_eq!(bytes, [137, 7, 4, 10, 0, 0, 2, END_OF_LIST]);
+        assert_eq!(
+            IpOptions::decode(&bytes).unwrap(),
+            IpOptions(vec![IpOption::StrictSourceRoute(vec![destination])])
+        );
+
+        // Linux moves the first hop out itself, and reuses its slot for
+        // the destination.
+        let bytes = loose.encode_for_socket().unwrap();
+        assert_eq!(bytes, [131, 11, 4, 10, 0, 0, 7, 10, 0, 0, 8, END_OF_LIST]);
+    }
+
+    #[test]
+    fn record_route_and_timestamps_round_trip() {
+        let destination = ip("10.0.0.2");
+        let bytes = options(&["record:2"]).encode(destination).unwrap();
+        assert_eq!(bytes, [7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, END_OF_LIST]);
+        assert_eq!(
+            IpOptions::decode(&bytes).unwrap(),
+            IpOptions(vec![IpOption::RecordRoute {
+                slots: 2,
+                recorded: Vec::new(),
+            }])
+        );
+
+        let bytes = options(&["timestamp:2"]).encode(destination).unwrap();
+        assert_eq!(bytes, [68, 12, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let bytes = options(&["timestamp-addr:1"]).encode(destination).unwrap();
+        assert_eq!(bytes, [68, 12, 5, TS_WITH_ADDRESS, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            IpOptions::decode(&bytes).unwrap(),
+            IpOptions(vec![IpOption::Timestamp {
+                addresses: true,
+                slots: 1,
+                overflow: 0,
+                recorded: Vec::new(),
+            }])
+        );
+    }
+
+    #[test]
+    fn unsized_options_share_the_40_bytes() {
+        let destination = ip("10.0.0.2");
+        let sized = |specs: &[&str]| -> Vec<usize> {
+            let options = IpOptions::decode(&options(specs).encode(destination).unwrap());
+            options
+                .unwrap()
+                .0
+                .iter()
+                .map(|option| match option {
+                    IpOption::RecordRoute { slots, .. } | IpOption::Timestamp { slots, .. } => {
+                        *slots
+                    }
+                    other => panic!("unexpected option {:?}", other),
+                })
+                .collect()
+        };
+
+        assert_eq!(sized(&["record"]), [9]);
+        assert_eq!(sized(&["timestamp"]), [9]);
+        assert_eq!(sized(&["timestamp-addr"]), [4]);
+        assert_eq!(sized(&["record", "timestamp"]), [4, 4]);
+        assert_eq!(sized(&["record:2", "timestamp"]), [2, 6]);
+        for specs in &[
+            &["record"][..],
+            &["record", "timestamp"],
+            &["timestamp-addr"],
+        ] {
+            assert!(options(specs).encode(destination).unwrap().len() <= MAX_LEN);
+        }
+    }
+
+    #[test]
+    fn options_past_40_bytes_are_rejected() {
+        let destination = ip("10.0.0.2");
+        let hops: Vec<String> = (1..=10).map(|i| format!("10.0.1.{}", i)).collect();
+        // Nine hops after the first, plus the destination: 43 bytes.
+        let loose = format!("loose:{}", hops.join(","));
+        // Exactly 40 bytes.
+        let fits = format!("loose:{}", hops[..9].join(","));
+        assert_eq!(
+            options(&[&fits]).encode(destination).unwrap().len(),
+            MAX_LEN
+        );
+
+        for specs in &[
+            vec![loose.as_str()],
+            vec!["record:10"],
+            vec!["timestamp-addr:5"],
+            // The route leaves too little room to record anything.
+            vec![fits.as_str(), "record"],
+        ] {
+            assert!(
+                matches!(
+                    options(specs).encode(destination),
+                    Err(Error::IpOption { .. })
+                ),
+                "{:?} should not fit",
+                specs
+            );
+        }
+    }
+
+    #[test]
+    fn captured_record_route_decodes() {
+        // An echo reply on loopback, after four slots were filled.
+        let options = captured(concat!(
+            "4a00003281db00004001da5d7f0000017f000001",
+            "0713147f0000017f0000017f0000017f00000100",
+        ));
+        let localhost = Ipv4Addr::LOCALHOST;
+        assert_eq!(
+            options,
+            IpOptions(vec![IpOption::RecordRoute {
+                slots: 4,
+                recorded: vec![localhost; 4],
+            }])
+        );
+        assert_eq!(
+            options.to_string(),
+            "RR (4/4) 127.0.0.1,127.0.0.1,127.0.0.1,127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn captured_timestamp_decodes_its_overflow() {
+        // An echo reply on loopback whose one slot filled on the way
+        // out, so two hops after it counted themselves as overflow.
+        let options = captured(concat!(
+            "4800002a81dd0000400168747f0000017f000001",
+            "440c0d217f0000010227bd39",
+        ));
+        assert_eq!(
+            options,
+            IpOptions(vec![IpOption::Timestamp {
+                addresses: true,
+                slots: 1,
+                overflow: 2,
+                recorded: vec![(Some(Ipv4Addr::LOCALHOST), 0x0227_bd39)],
+            }])
+        );
+    }
+
+    #[test]
+    fn pointer_limits_what_was_recorded() {
+        // Only the first of two slots filled: the pointer is at the
+        // second.
+        let half = [RECORD_ROUTE, 11, 8, 10, 0, 0, 1, 10, 0, 0, 2];
+        // Pointers past the end, or before the first slot, are clamped.
+        let past = [RECORD_ROUTE, 7, 200, 10, 0, 0, 1];
+        let before = [RECORD_ROUTE, 7, 0, 10, 0, 0, 1];
+        let recorded = |bytes: &[u8]| match &IpOptions::decode(bytes).unwrap().0[..] {
+            [IpOption::RecordRoute { recorded, .. }] => recorded.clone(),
+            other => panic!("expected a record route, got {:?}", other),
+        };
+        assert_eq!(recorded(&half), [ip("10.0.0.1")]);
+        assert_eq!(recorded(&past), [ip("10.0.0.1")]);
+        assert!(recorded(&before).is_empty());
+
+        // No-ops are skipped.
+        let padded = [
+            NO_OPERATION,
+            NO_OPERATION,
+            RECORD_ROUTE,
+            7,
+            8,
+            10,
+            0,
+            0,
+            1,
+            END_OF_LIST,
+        ];
+        assert_eq!(recorded(&padded), [ip("10.0.0.1")]);
+    }
+
+    #[test]
+    fn malformed_options_are_errors() {
+        for bytes in &[
+            &[RECORD_ROUTE][..],
+            &[RECORD_ROUTE, 1],
+            &[RECORD_ROUTE, 9, 4, 0],
+        ] {
+            assert!(matches!(
+                IpOptions::decode(bytes),
+                Err(Error::IpOption { .. })
+            ));
+        }
+    }
+}
@@ -1,51 +1,175 @@
-use thiserror::Error;
+use etherparse::IpHeader;
 use rand::Rng;
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::io::Error as IoErr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::{self, FromStr};
+use std::time::Duration;
 
-#[derive(Error, Debug)]
-pub enum ParseErr {
-    #[error("Invalid protocol")]
-    Protocol,
-}
+pub use error::Error;
+/// Re-exported for the header types datagrams carry.
+pub use etherparse;
+pub use packet::{PacketOptions, TcpFlags};
+pub use session::SessionBuilder;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     Udp,
     Tcp,
 }
 
 impl FromStr for Protocol {
-    type Err = ParseErr;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "udp" => Ok(Protocol::Udp),
             "tcp" => Ok(Protocol::Tcp),
-            _ => Err(ParseErr::Protocol),
+            _ => Err(Error::Protocol(s.to_string())),
         }
     }
 }
 
 impl Display for Protocol {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", &self.to_string()[..])
+        match self {
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+impl Protocol {
+    /// Runs `task` with the session type that implements this protocol.
+    ///
+    /// This is the one place a protocol is mapped to a session type;
+    /// code written against `Session` picks up new protocols for free.
+    pub fn dispatch<T: WithSession>(self, task: T) -> T::Output {
+        match self {
+            Protocol::Udp => task.run::<udp::UdpSession>(),
+            Protocol::Tcp => task.run::<tcp::TcpSession>(),
+        }
+    }
+}
+
+pub trait Sender {
+    fn send(&mut self, data: &[u8]) -> Result<usize, Error>;
+
+    /// Sends each payload in turn, returning how many were sent.
+    /// Transports that can batch the syscalls override this.
+    fn send_many(&mut self, payloads: &[&[u8]]) -> Result<usize, Error> {
+        for payload in payloads {
+            self.send(payload)?;
+        }
+        Ok(payloads.len())
+    }
+}
+
+pub trait Receiver {
+    type Datagram: Datagram;
+
+    /// Waits up to `wait_time` for the next datagram, returning it
+    /// along with how long the wait took.
+    fn receive(&mut self, wait_time: Duration) -> Result<(Self::Datagram, Duration), Error>;
+
+    /// Waits up to `wait_time` for a datagram, then returns it along
+    /// with up to `max - 1` more that are already queued. Transports
+    /// that can batch the syscalls override this; others return one.
+    fn receive_many(
+        &mut self,
+        max: usize,
+        wait_time: Duration,
+    ) -> Result<Vec<Self::Datagram>, Error> {
+        let _ = max;
+        let (datagram, _read_time) = self.receive(wait_time)?;
+        Ok(vec![datagram])
+    }
+
+    /// Receives like `receive_many`, but only returns how many
+    /// datagrams arrived and their total length. Transports that read
+    /// into reusable buffers override this to skip copying them out.
+    fn receive_count(&mut self, max: usize, wait_time: Duration) -> Result<(usize, usize), Error> {
+        let datagrams = self.receive_many(max, wait_time)?;
+        Ok((datagrams.len(), datagrams.iter().map(Datagram::len).sum()))
     }
 }
 
-pub trait Sender<'a> {
-    fn send(&mut self, data: &'a [u8]) -> Result<usize, IoErr>;
+/// What every received datagram has, whatever its transport.
+pub trait Datagram {
+    fn data(&self) -> &[u8];
+
+    /// The IP header the datagram arrived with, if the transport can
+    /// see it.
+    fn ip_header(&self) -> Option<&IpHeader> {
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.data().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data().is_empty()
+    }
+}
+
+/// A transport the subcommands can be written against.
+pub trait Session: Sender + Receiver + Send + Sized + 'static {
+    const PROTOCOL: Protocol;
+
+    fn build(builder: &SessionBuilder) -> Result<Self, Error>;
+
+    /// Waits up to `wait_time` for a peer to connect, returning how
+    /// long that took; connectionless transports return `None`.
+    fn accept(&mut self, wait_time: Duration) -> Result<Option<Duration>, Error>;
 }
 
-pub fn random_local() -> String {
-    let mut local = String::from("127.0.0.1:");
+/// Work that is generic over the session type; see `Protocol::dispatch`.
+pub trait WithSession {
+    type Output;
+
+    fn run<S: Session>(self) -> Self::Output;
+}
+
+/// Returns a random port on the loopback address of the same
+/// IP version as `peer`.
+pub fn random_local(peer: IpAddr) -> SocketAddr {
+    random_port(loopback(peer))
+}
+
+/// Returns `ip` with a random port.
+pub fn random_port(ip: IpAddr) -> SocketAddr {
     let mut rng = rand::thread_rng();
     let port: u16 = rng.gen_range(2000, 65353);
-    local.push_str(&port.to_string()[..]);
-    local
+    SocketAddr::new(ip, port)
+}
+
+/// Returns the loopback address of the same IP version as `peer`.
+pub fn loopback(peer: IpAddr) -> IpAddr {
+    match peer {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    }
 }
 
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod banner;
+pub mod batch;
+pub mod diag;
+pub mod duration;
+mod error;
+mod errqueue;
+pub mod interrupt;
+pub mod ipopt;
+mod packet;
+mod poll;
+pub mod procnet;
+mod raw;
+pub mod resolve;
+pub mod scan;
+pub mod services;
+mod session;
+pub mod target;
 pub mod tcp;
 pub mod udp;
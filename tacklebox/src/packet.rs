@@ -0,0 +1,440 @@
+extern crate socket2;
+use etherparse::TcpHeader;
+use etherparse::{
+    IpHeader, IpTrafficClass, Ipv4Header, Ipv6Header, PacketBuilder, PacketBuilderStep,
+};
+use socket2::SockRef;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+#[cfg(target_os = "linux")]
+use std::{io::Error as IoErr, mem, os::unix::io::AsRawFd};
+
+use crate::ipopt::IpOptions;
+use crate::Error;
+
+/// The TTL crafted packets get when none is given.
+const DEFAULT_TTL: u8 = 64;
+
+/// Header fields for the packets a session sends.
+///
+/// Raw sessions write every field into the packets they build. Plain
+/// sessions hand the IP fields they can (TTL, TOS and DF) to the kernel
+/// as socket options; the rest are the kernel's to choose.
+#[derive(Debug, Clone)]
+pub struct PacketOptions {
+    /// TTL, or hop limit for IPv6. Defaults to the system's for plain
+    /// sockets and 64 for raw ones.
+    pub ttl: Option<u8>,
+    /// TOS byte for IPv4 (DSCP and ECN), traffic class for IPv6.
+    pub tos: u8,
+    /// Sets or clears the Don't Fragment bit; left alone if `None`.
+    pub dont_fragment: Option<bool>,
+    /// IPv4 ID of the next packet. Advances with every send.
+    pub identification: u16,
+    pub flags: TcpFlags,
+    /// Sequence number of the next segment. Advances with every send.
+    pub sequence_number: u32,
+    /// Only sent when `flags.ack` is set.
+    pub acknowledgment_number: u32,
+    pub window_size: u16,
+    /// Only sent when `flags.urg` is set.
+    pub urgent_pointer: u16,
+    /// IPv4 options such as a source route. Not available over IPv6.
+    pub ip_options: IpOptions,
+}
+
+impl Default for PacketOptions {
+    fn default() -> PacketOptions {
+        PacketOptions {
+            ttl: None,
+            tos: 0,
+            dont_fragment: None,
+            identification: 0,
+            flags: TcpFlags::default(),
+            sequence_number: 1,
+            acknowledgment_number: 0,
+            window_size: u16::MAX,
+            urgent_pointer: 0,
+            ip_options: IpOptions::default(),
+        }
+    }
+}
+
+impl PacketOptions {
+    /// Starts a `PacketBuilder` for the IP version of `source`/`destination`.
+    ///
+    /// If the two addresses differ in family, the IPv4 one is written as an
+    /// IPv4-mapped IPv6 address and an IPv6 header is built.
+    ///
+    /// A source route is written with the final destination in its last
+    /// slot; see `route` for pointing the packet at the first hop.
+    pub(crate) fn ip_builder(
+        &self,
+        source: IpAddr,
+        destination: IpAddr,
+    ) -> Result<PacketBuilderStep<IpHeader>, Error> {
+        let ttl = self.ttl.unwrap_or(DEFAULT_TTL);
+        let header = match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                // The builder fills in the length and protocol.
+                let mut header = Ipv4Header::new(
+                    0,
+                    ttl,
+                    IpTrafficClass::Tcp,
+                    source.octets(),
+                    destination.octets(),
+                );
+                header.differentiated_services_code_point = self.tos >> 2;
+                header.explicit_congestion_notification = self.tos & 0b11;
+                header.identification = self.identification;
+                if let Some(dont_fragment) = self.dont_fragment {
+                    header.dont_fragment = dont_fragment;
+                }
+                let options = self.ip_options.encode(destination)?;
+                header.set_options(&options).map_err(|e| Error::IpOption {
+                    option: self.ip_options.to_string(),
+                    reason: e.to_string(),
+                })?;
+                IpHeader::Version4(header)
+            }
+            _ if !self.ip_options.is_empty() => {
+                return Err(Error::Unsupported("IP options over IPv6"))
+            }
+            (source, destination) => IpHeader::Version6(Ipv6Header {
+                traffic_class: self.tos,
+                hop_limit: ttl,
+                source: to_ipv6(source).octets(),
+                destination: to_ipv6(destination).octets(),
+                ..Default::default()
+            }),
+        };
+        Ok(PacketBuilder::ip(header))
+    }
+
+    /// Points a packet written by a builder from `ip_builder` at the
+    /// first hop of its source route, if it has one.
+    ///
+    /// The builder has to see the final destination so that the
+    /// transport checksum comes out right, as RFC 791 asks.
+    pub(crate) fn route(&self, packet: &mut [u8]) {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return;
+        }
+        let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let first_hop = self.ip_options.first_hop(destination);
+        if first_hop == destination {
+            return;
+        }
+        packet[16..20].copy_from_slice(&first_hop.octets());
+
+        let header_len = ((packet[0] & 0x0f) as usize * 4).min(packet.len());
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let checksum = header_checksum(&packet[..header_len]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Sets the TCP fields on a builder started by `ip_builder`.
+    pub(crate) fn tcp_builder(
+        &self,
+        ip: PacketBuilderStep<IpHeader>,
+        source_port: u16,
+        destination_port: u16,
+    ) -> PacketBuilderStep<TcpHeader> {
+        let mut builder = ip.tcp(
+            source_port,
+            destination_port,
+            self.sequence_number,
+            self.window_size,
+        );
+        let flags = self.flags;
+        if flags.syn {
+            builder = builder.syn();
+        }
+        if flags.ack {
+            builder = builder.ack(self.acknowledgment_number);
+        }
+        if flags.fin {
+            builder = builder.fin();
+        }
+        if flags.rst {
+            builder = builder.rst();
+        }
+        if flags.psh {
+            builder = builder.psh();
+        }
+        if flags.urg {
+            builder = builder.urg(self.urgent_pointer);
+        }
+        builder
+    }
+
+    /// Readies the fields for the packet after one carrying
+    /// `payload_len` bytes: the IP ID goes up by one, and the sequence
+    /// number moves past the payload, plus one each for SYN and FIN.
+    pub(crate) fn advance(&mut self, payload_len: usize) {
+        self.identification = self.identification.wrapping_add(1);
+        let consumed = payload_len as u32 + self.flags.syn as u32 + self.flags.fin as u32;
+        self.sequence_number = self.sequence_number.wrapping_add(consumed);
+    }
+
+    /// Sets the IP fields a plain socket can carry on `socket`, which
+    /// talks to or is bound to `addr`.
+    pub(crate) fn apply<'s>(
+        &self,
+        socket: impl Into<SockRef<'s>>,
+        addr: SocketAddr,
+    ) -> Result<(), Error> {
+        let socket = socket.into();
+        match addr {
+            SocketAddr::V4(_) => {
+                if let Some(ttl) = self.ttl {
+                    socket.set_ttl(ttl.into())?;
+                }
+                if self.tos != 0 {
+                    socket.set_tos(self.tos.into())?;
+                }
+            }
+            SocketAddr::V6(_) => {
+                if let Some(ttl) = self.ttl {
+                    socket.set_unicast_hops_v6(ttl.into())?;
+                }
+                if self.tos != 0 {
+                    set_tclass_v6(&socket, self.tos)?;
+                }
+            }
+        }
+        if let Some(dont_fragment) = self.dont_fragment {
+            set_dont_fragment(&socket, addr, dont_fragment)?;
+        }
+        if !self.ip_options.is_empty() {
+            if addr.is_ipv6() {
+                return Err(Error::Unsupported("IP options over IPv6"));
+            }
+            set_ip_options(&socket, &self.ip_options.encode_for_socket()?)?;
+        }
+        Ok(())
+    }
+}
+
+/// The flags a crafted TCP segment carries.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TcpFlags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub urg: bool,
+}
+
+impl FromStr for TcpFlags {
+    type Err = Error;
+
+    /// Parses a comma-separated list of flag names such as `syn,ack`,
+    /// or `none`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = TcpFlags::default();
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(flags);
+        }
+        for name in s.split(',').map(str::trim) {
+            let flag = match &*name.to_ascii_lowercase() {
+                "syn" => &mut flags.syn,
+                "ack" => &mut flags.ack,
+                "fin" => &mut flags.fin,
+                "rst" => &mut flags.rst,
+                "psh" => &mut flags.psh,
+                "urg" => &mut flags.urg,
+                _ => return Err(Error::TcpFlags(s.to_string())),
+            };
+            *flag = true;
+        }
+        Ok(flags)
+    }
+}
+
+impl Display for TcpFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.syn, "SYN"),
+            (self.ack, "ACK"),
+            (self.fin, "FIN"),
+            (self.rst, "RST"),
+            (self.psh, "PSH"),
+            (self.urg, "URG"),
+        ];
+        let set: Vec<&str> = names
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect();
+        if set.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", set.join(","))
+        }
+    }
+}
+
+/// The one's complement sum of `header`'s 16-bit words.
+fn header_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0)))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tclass_v6(socket: &SockRef, tclass: u8) -> Result<(), Error> {
+    Ok(socket.set_tclass_v6(tclass.into())?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tclass_v6(_socket: &SockRef, _tclass: u8) -> Result<(), Error> {
+    Err(Error::Unsupported(
+        "setting the IPv6 traffic class outside Linux",
+    ))
+}
+
+/// Turns path MTU discovery, and with it the DF bit, on or off.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &SockRef, addr: SocketAddr, on: bool) -> Result<(), Error> {
+    let (level, name, value) = match (addr, on) {
+        (SocketAddr::V4(_), true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        (SocketAddr::V4(_), false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DONT,
+        ),
+        (SocketAddr::V6(_), true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+        (SocketAddr::V6(_), false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DONT,
+        ),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoErr::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &SockRef, _addr: SocketAddr, _on: bool) -> Result<(), Error> {
+    Err(Error::Unsupported("setting the DF bit outside Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_ip_options(socket: &SockRef, options: &[u8]) -> Result<(), Error> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_OPTIONS,
+            options.as_ptr() as *const libc::c_void,
+            options.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoErr::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ip_options(_socket: &SockRef, _options: &[u8]) -> Result<(), Error> {
+    Err(Error::Unsupported("IP options outside Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a segment from `options` and reads its headers back.
+    fn build(options: &PacketOptions, payload: &[u8]) -> (Ipv4Header, TcpHeader) {
+        let (source, destination) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let ip = options.ip_builder(source, destination).unwrap();
+        let builder = options.tcp_builder(ip, 40000, 80);
+        let mut packet = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut packet, payload).unwrap();
+
+        let (ip, transport) = IpHeader::read_from_slice(&packet).unwrap();
+        let (tcp, _) = TcpHeader::read_from_slice(transport).unwrap();
+        match ip {
+            IpHeader::Version4(ip) => (ip, tcp),
+            other => panic!("expected an IPv4 header, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn advance_moves_id_and_sequence() {
+        let mut options = PacketOptions {
+            ttl: Some(7),
+            identification: u16::MAX,
+            flags: TcpFlags {
+                syn: true,
+                ..TcpFlags::default()
+            },
+            sequence_number: 1000,
+            ..PacketOptions::default()
+        };
+
+        // The SYN takes up a sequence number of its own.
+        let (ip, tcp) = build(&options, b"");
+        assert_eq!((ip.identification, tcp.sequence_number), (u16::MAX, 1000));
+        options.advance(0);
+
+        options.flags = "psh,ack".parse().unwrap();
+        let (ip, tcp) = build(&options, b"hello");
+        assert_eq!((ip.identification, tcp.sequence_number), (0, 1001));
+        assert!(tcp.psh && tcp.ack && !tcp.syn);
+        options.advance(5);
+
+        let (ip, tcp) = build(&options, b"world!");
+        assert_eq!((ip.identification, tcp.sequence_number), (1, 1006));
+        options.advance(6);
+
+        // A FIN counts for one too, and sequence numbers wrap.
+        options.flags = "fin".parse().unwrap();
+        options.sequence_number = u32::MAX - 1;
+        let (ip, tcp) = build(&options, b"!");
+        assert_eq!((ip.identification, tcp.sequence_number), (2, u32::MAX - 1));
+        options.advance(1);
+        assert_eq!(options.sequence_number, 0);
+
+        // The TTL stays as given throughout.
+        assert_eq!(ip.time_to_live, 7);
+        assert_eq!(build(&options, b"").0.time_to_live, 7);
+    }
+}
@@ -0,0 +1,67 @@
+extern crate libc;
+use std::io::Error as IoErr;
+use std::io::ErrorKind as IoErrKind;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// Sleeps until `fd` has data to read, or `timeout` passes.
+///
+/// Returns `false` on timeout. Interrupted polls are restarted with
+/// whatever time is left, so the timeout holds across signals. A
+/// timeout too far off to represent waits forever.
+pub(crate) fn wait_readable<F: AsRawFd>(fd: &F, timeout: Duration) -> Result<bool, IoErr> {
+    let deadline = Instant::now().checked_add(timeout);
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        let millis = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // Round up so we never wake before the deadline.
+                remaining
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+
+        match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+            -1 => {
+                let e = IoErr::last_os_error();
+                if e.kind() != IoErrKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            0 => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(false);
+                }
+            }
+            _ => return Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn huge_timeout_does_not_overflow() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"x", socket.local_addr().unwrap()).unwrap();
+        assert!(wait_readable(&socket, Duration::MAX).unwrap());
+    }
+
+    #[test]
+    fn times_out() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(!wait_readable(&socket, Duration::from_millis(20)).unwrap());
+    }
+}
@@ -0,0 +1,560 @@
+//! Socket tables from `/proc/net`, and the processes holding each
+//! socket, read natively rather than through `lsof` or `netstat`.
+
+extern crate libc;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::ErrorKind as IoErrKind;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{Error, Protocol};
+
+/// A socket's state, as the kernel numbers them in `tcp_states.h`.
+/// UDP sockets are `Close`, or `Established` once connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketState {
+    Established,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+}
+
+impl SocketState {
+    /// Every state, in the kernel's order.
+    pub const ALL: [SocketState; 12] = [
+        SocketState::Established,
+        SocketState::SynSent,
+        SocketState::SynRecv,
+        SocketState::FinWait1,
+        SocketState::FinWait2,
+        SocketState::TimeWait,
+        SocketState::Close,
+        SocketState::CloseWait,
+        SocketState::LastAck,
+        SocketState::Listen,
+        SocketState::Closing,
+        SocketState::NewSynRecv,
+    ];
+
+    fn from_code(code: u8) -> Option<SocketState> {
+        SocketState::ALL
+            .get(usize::from(code).checked_sub(1)?)
+            .copied()
+    }
+}
+
+impl FromStr for SocketState {
+    type Err = Error;
+    /// Accepts the displayed names in any case, with `-` for `_`, so
+    /// `listen` and `time-wait` both work.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.replace('-', "_");
+        SocketState::ALL
+            .iter()
+            .find(|state| state.to_string().eq_ignore_ascii_case(&name))
+            .copied()
+            .ok_or_else(|| Error::SocketState(s.to_string()))
+    }
+}
+
+impl Display for SocketState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SocketState::Established => "ESTABLISHED",
+            SocketState::SynSent => "SYN_SENT",
+            SocketState::SynRecv => "SYN_RECV",
+            SocketState::FinWait1 => "FIN_WAIT1",
+            SocketState::FinWait2 => "FIN_WAIT2",
+            SocketState::TimeWait => "TIME_WAIT",
+            SocketState::Close => "CLOSE",
+            SocketState::CloseWait => "CLOSE_WAIT",
+            SocketState::LastAck => "LAST_ACK",
+            SocketState::Listen => "LISTEN",
+            SocketState::Closing => "CLOSING",
+            SocketState::NewSynRecv => "NEW_SYN_RECV",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One row of a `/proc/net` socket table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socket {
+    pub protocol: Protocol,
+    pub local: SocketAddr,
+    /// The unspecified address and port 0 when not connected.
+    pub remote: SocketAddr,
+    pub state: SocketState,
+    /// Bytes waiting to be sent, or acknowledged.
+    pub tx_queue: u32,
+    /// Bytes waiting to be read.
+    pub rx_queue: u32,
+    pub uid: u32,
+    /// 0 for sockets no longer tied to a file, like those in `TIME_WAIT`.
+    pub inode: u64,
+    /// Processes with the socket open; empty if there are none, or
+    /// they can't be seen without more privileges.
+    pub pids: Vec<u32>,
+}
+
+impl Socket {
+    /// Whether the socket has a peer.
+    pub fn is_connected(&self) -> bool {
+        self.remote.port() != 0
+    }
+}
+
+/// A process holding a socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    /// The executable name, as `ps -o comm` shows it.
+    pub command: String,
+    /// The arguments the process was started with; empty for kernel
+    /// threads.
+    pub cmdline: Vec<String>,
+}
+
+/// Reads socket tables from a `/proc` tree.
+#[derive(Debug, Clone)]
+pub struct ProcNet {
+    root: PathBuf,
+}
+
+impl Default for ProcNet {
+    fn default() -> Self {
+        ProcNet {
+            root: PathBuf::from("/proc"),
+        }
+    }
+}
+
+impl ProcNet {
+    pub fn new() -> ProcNet {
+        ProcNet::default()
+    }
+
+    /// Read from the `/proc` tree at `root` instead (default `/proc`).
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> ProcNet {
+        self.root = root.into();
+        self
+    }
+
+    /// Every TCP and UDP socket, over IPv4 and IPv6, with the
+    /// processes holding it.
+    pub fn sockets(&self) -> Result<Vec<Socket>, Error> {
+        let mut sockets = Vec::new();
+        let tables = [
+            ("tcp", Protocol::Tcp),
+            ("tcp6", Protocol::Tcp),
+            ("udp", Protocol::Udp),
+            ("udp6", Protocol::Udp),
+        ];
+        for (table, protocol) in &tables {
+            sockets.extend(self.table(table, *protocol)?);
+        }
+
+        let owners = self.owners()?;
+        for socket in &mut sockets {
+            if let Some(pids) = owners.get(&socket.inode) {
+                socket.pids = pids.clone();
+            }
+        }
+        Ok(sockets)
+    }
+
+    /// The command name and arguments of `pid`, or `None` if it has
+    /// exited.
+    pub fn process(&self, pid: u32) -> Option<Process> {
+        let dir = self.root.join(pid.to_string());
+        let command = fs::read_to_string(dir.join("comm")).ok()?;
+        let cmdline = fs::read(dir.join("cmdline")).unwrap_or_default();
+        Some(Process {
+            pid,
+            command: command.trim_end().to_string(),
+            cmdline: cmdline
+                .split(|&b| b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
+        })
+    }
+
+    /// Parses `net/<name>`. Missing tables, as `tcp6` is when IPv6 is
+    /// disabled, are empty; lines that don't parse are skipped.
+    fn table(&self, name: &str, protocol: Protocol) -> Result<Vec<Socket>, Error> {
+        let text = match fs::read_to_string(self.root.join("net").join(name)) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == IoErrKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(text
+            .lines()
+            .skip(1)
+            .filter_map(|line| parse_line(line, protocol))
+            .collect())
+    }
+
+    /// Maps socket inodes to the processes with them open, by reading
+    /// the `socket:[inode]` links in each `/proc/<pid>/fd`. Processes
+    /// that exit midway, or whose descriptors can't be read, are
+    /// passed over.
+    fn owners(&self) -> Result<HashMap<u64, Vec<u32>>, Error> {
+        let mut owners: HashMap<u64, Vec<u32>> = HashMap::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let pid = match entry.file_name().to_str().map(u32::from_str) {
+                Some(Ok(pid)) => pid,
+                _ => continue,
+            };
+            let fds = match fs::read_dir(entry.path().join("fd")) {
+                Ok(fds) => fds,
+                Err(_) => continue,
+            };
+            for fd in fds.flatten() {
+                if let Some(inode) = socket_inode(&fd.path()) {
+                    let pids = owners.entry(inode).or_default();
+                    if !pids.contains(&pid) {
+                        pids.push(pid);
+                    }
+                }
+            }
+        }
+        for pids in owners.values_mut() {
+            pids.sort_unstable();
+        }
+        Ok(owners)
+    }
+}
+
+/// The name of the user with `uid`, from the system's user database.
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        // Entries with long fields need a bigger buffer.
+        if ret == libc::ERANGE && buf.len() < 1 << 16 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        break;
+    }
+    if result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// The inode of the socket a descriptor link points at, if it is one.
+fn socket_inode(fd: &Path) -> Option<u64> {
+    let target = fs::read_link(fd).ok()?;
+    let inode = target
+        .to_str()?
+        .strip_prefix("socket:[")?
+        .strip_suffix(']')?;
+    inode.parse().ok()
+}
+
+/// Parses a table row:
+/// `sl local rem st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...`
+fn parse_line(line: &str, protocol: Protocol) -> Option<Socket> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    let (tx_queue, rx_queue) = fields[4].split_once(':')?;
+    Some(Socket {
+        protocol,
+        local: parse_addr(fields[1])?,
+        remote: parse_addr(fields[2])?,
+        state: SocketState::from_code(u8::from_str_radix(fields[3], 16).ok()?)?,
+        tx_queue: u32::from_str_radix(tx_queue, 16).ok()?,
+        rx_queue: u32::from_str_radix(rx_queue, 16).ok()?,
+        uid: fields[7].parse().ok()?,
+        inode: fields[9].parse().ok()?,
+        pids: Vec::new(),
+    })
+}
+
+/// Parses `0100007F:0016` or its 32-digit IPv6 form. Addresses are
+/// printed as 32-bit words in host byte order; ports are plain hex.
+fn parse_addr(field: &str) -> Option<SocketAddr> {
+    let (ip, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut octets = Vec::with_capacity(16);
+    for word in 0..ip.len() / 8 {
+        let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match (ip.len(), octets.len()) {
+        (8, 4) => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+        (32, 16) => {
+            let mut v6 = [0u8; 16];
+            v6.copy_from_slice(&octets);
+            IpAddr::V6(Ipv6Addr::from(v6))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const HEADER: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when \
+                          retrnsmt   uid  timeout inode";
+
+    /// A `/proc` tree in the temp directory, removed when dropped.
+    struct FakeProc {
+        root: PathBuf,
+    }
+
+    impl FakeProc {
+        fn new() -> FakeProc {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .subsec_nanos();
+            let name = format!(
+                "tacklebox-procnet-{}-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst),
+                nanos
+            );
+            let root = std::env::temp_dir().join(name);
+            fs::create_dir_all(root.join("net")).unwrap();
+            FakeProc { root }
+        }
+
+        fn table(&self, name: &str, rows: &[&str]) {
+            let mut text = format!("{}\n", HEADER);
+            for row in rows {
+                text.push_str(row);
+                text.push('\n');
+            }
+            fs::write(self.root.join("net").join(name), text).unwrap();
+        }
+
+        /// A process with descriptors pointing at `targets`.
+        fn process(&self, pid: u32, command: &str, targets: &[&str]) {
+            let dir = self.root.join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).unwrap();
+            fs::write(dir.join("comm"), format!("{}\n", command)).unwrap();
+            fs::write(dir.join("cmdline"), format!("{}\0--port\08080\0", command)).unwrap();
+            for (fd, target) in targets.iter().enumerate() {
+                symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+            }
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // `/proc/net` prints each 32-bit word of an address in host byte
+    // order; these rows are as a little-endian kernel writes them.
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn reads_tables_and_owners() {
+        let proc = FakeProc::new();
+        proc.table(
+            "tcp",
+            &[
+                "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  \
+                 1000        0 41001 1 0000000000000000 100 0 0 10 0",
+                "   1: 0100007F:1F90 0100007F:C350 01 0000000A:00000014 00:00000000 00000000  \
+                 1000        0 41002 1 0000000000000000 20 4 30 10 -1",
+                "   2: 0200000A:0016 0300000A:D431 06 00000000:00000000 03:00000F1A 00000000     \
+                 0        0 0 3 0000000000000000",
+                "garbage",
+            ],
+        );
+        proc.table(
+            "tcp6",
+            &["   0: B80D0120000000000000000001000000:01BB \
+               00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 \
+               00000000     0        0 41003 1 0000000000000000 100 0 0 10 0"],
+        );
+        proc.table(
+            "udp",
+            &[
+                " 1234: 00000000:0035 00000000:0000 07 00000000:00000200 00:00000000 00000000   \
+               101        0 41004 2 0000000000000000 0",
+            ],
+        );
+        proc.table(
+            "udp6",
+            &[" 5678: 0000000000000000FFFF00000100007F:0035 \
+               0000000000000000FFFF00000200007F:A000 01 00000000:00000000 00:00000000 \
+               00000000   101        0 41005 2 0000000000000000 0"],
+        );
+        proc.process(
+            100,
+            "server",
+            &["socket:[41001]", "socket:[41002]", "/dev/null"],
+        );
+        proc.process(
+            200,
+            "worker",
+            &["socket:[41002]", "pipe:[9]", "socket:[41005]"],
+        );
+        // No descriptors to read, as for another user's process.
+        fs::create_dir_all(proc.root.join("300")).unwrap();
+
+        let sockets = ProcNet::new().root(&proc.root).sockets().unwrap();
+        let expected = vec![
+            Socket {
+                protocol: Protocol::Tcp,
+                local: addr("127.0.0.1:8080"),
+                remote: addr("0.0.0.0:0"),
+                state: SocketState::Listen,
+                tx_queue: 0,
+                rx_queue: 0,
+                uid: 1000,
+                inode: 41001,
+                pids: vec![100],
+            },
+            Socket {
+                protocol: Protocol::Tcp,
+                local: addr("127.0.0.1:8080"),
+                remote: addr("127.0.0.1:50000"),
+                state: SocketState::Established,
+                tx_queue: 10,
+                rx_queue: 20,
+                uid: 1000,
+                inode: 41002,
+                pids: vec![100, 200],
+            },
+            Socket {
+                protocol: Protocol::Tcp,
+                local: addr("10.0.0.2:22"),
+                remote: addr("10.0.0.3:54321"),
+                state: SocketState::TimeWait,
+                tx_queue: 0,
+                rx_queue: 0,
+                uid: 0,
+                inode: 0,
+                pids: vec![],
+            },
+            Socket {
+                protocol: Protocol::Tcp,
+                local: addr("[2001:db8::1]:443"),
+                remote: addr("[::]:0"),
+                state: SocketState::Listen,
+                tx_queue: 0,
+                rx_queue: 0,
+                uid: 0,
+                inode: 41003,
+                pids: vec![],
+            },
+            Socket {
+                protocol: Protocol::Udp,
+                local: addr("0.0.0.0:53"),
+                remote: addr("0.0.0.0:0"),
+                state: SocketState::Close,
+                tx_queue: 0,
+                rx_queue: 512,
+                uid: 101,
+                inode: 41004,
+                pids: vec![],
+            },
+            Socket {
+                protocol: Protocol::Udp,
+                local: addr("[::ffff:127.0.0.1]:53"),
+                remote: addr("[::ffff:127.0.0.2]:40960"),
+                state: SocketState::Established,
+                tx_queue: 0,
+                rx_queue: 0,
+                uid: 101,
+                inode: 41005,
+                pids: vec![200],
+            },
+        ];
+        assert_eq!(sockets, expected);
+        assert!(sockets[1].is_connected());
+        assert!(!sockets[0].is_connected());
+
+        let net = ProcNet::new().root(&proc.root);
+        assert_eq!(
+            net.process(100),
+            Some(Process {
+                pid: 100,
+                command: "server".to_string(),
+                cmdline: vec![
+                    "server".to_string(),
+                    "--port".to_string(),
+                    "8080".to_string()
+                ],
+            })
+        );
+        assert_eq!(net.process(999), None);
+
+        let root = proc.root.clone();
+        drop(proc);
+        assert!(!root.exists(), "{} was left behind", root.display());
+    }
+
+    #[test]
+    fn missing_tables_are_empty() {
+        let proc = FakeProc::new();
+        proc.table("tcp", &[]);
+        let sockets = ProcNet::new().root(&proc.root).sockets().unwrap();
+        assert!(sockets.is_empty());
+    }
+
+    #[test]
+    fn states_parse_from_their_names() {
+        assert_eq!(
+            "listen".parse::<SocketState>().unwrap(),
+            SocketState::Listen
+        );
+        assert_eq!(
+            "time-wait".parse::<SocketState>().unwrap(),
+            SocketState::TimeWait
+        );
+        assert_eq!(
+            "FIN_WAIT1".parse::<SocketState>().unwrap(),
+            SocketState::FinWait1
+        );
+        assert!(matches!(
+            "waiting".parse::<SocketState>(),
+            Err(Error::SocketState(_))
+        ));
+        assert_eq!(SocketState::from_code(0x0A), Some(SocketState::Listen));
+        assert_eq!(SocketState::from_code(0), None);
+        assert_eq!(SocketState::from_code(13), None);
+    }
+}
@@ -0,0 +1,161 @@
+extern crate socket2;
+use etherparse::{IpHeader, Ipv4Header};
+use socket2::{Domain, Socket, Type};
+use std::io::Error as IoErr;
+use std::io::ErrorKind as IoErrKind;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use std::{mem, os::unix::io::AsRawFd};
+
+use crate::poll::wait_readable;
+use crate::{Error, Protocol};
+
+/// A raw IP socket that sends the packets tacklebox builds as they are
+/// and reads back the headers the peer actually sent.
+///
+/// Opening one needs root or `CAP_NET_RAW`.
+pub(crate) struct RawSocket {
+    socket: Socket,
+    local: SocketAddr,
+    buf: Vec<u8>,
+}
+
+impl RawSocket {
+    /// Opens a raw `protocol` socket on `local`'s address. Only packets
+    /// addressed to `local`'s port are received.
+    pub(crate) fn new(local: SocketAddr, protocol: Protocol) -> Result<RawSocket, Error> {
+        let transport = match protocol {
+            Protocol::Tcp => socket2::Protocol::TCP,
+            Protocol::Udp => socket2::Protocol::UDP,
+        };
+        let socket = Socket::new(Domain::for_address(local), Type::RAW, Some(transport))
+            .map_err(Error::RawSocket)?;
+
+        // Every packet we send carries its own IP header.
+        match local {
+            SocketAddr::V4(_) => socket.set_header_included_v4(true)?,
+            SocketAddr::V6(_) => include_ipv6_header(&socket)?,
+        }
+
+        let addr = SocketAddr::new(local.ip(), 0);
+        socket
+            .bind(&addr.into())
+            .map_err(|source| Error::Bind { addr, source })?;
+
+        Ok(RawSocket {
+            socket,
+            local,
+            buf: vec![0u8; 65536],
+        })
+    }
+
+    /// Sends a packet built with `PacketOptions::ip_builder` to the
+    /// destination in its own header, which is the first hop if the
+    /// packet is source routed.
+    pub(crate) fn send(&self, packet: &[u8]) -> Result<usize, Error> {
+        let destination: IpAddr = match (packet.get(16..20), packet.get(24..40)) {
+            (Some(v4), _) if packet[0] >> 4 == 4 => [v4[0], v4[1], v4[2], v4[3]].into(),
+            (_, Some(v6)) if packet[0] >> 4 == 6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(v6);
+                octets.into()
+            }
+            _ => {
+                return Err(Error::Io(IoErr::new(
+                    IoErrKind::InvalidInput,
+                    "not an IP packet",
+                )))
+            }
+        };
+        let destination = SocketAddr::new(destination, 0);
+        Ok(self.socket.send_to(packet, &destination.into())?)
+    }
+
+    /// Waits up to `wait_time` for a packet addressed to our port,
+    /// returning its IP header and the transport header and payload.
+    ///
+    /// Linux hands raw IPv6 sockets the packet without its IP header,
+    /// so there is only an IP header for IPv4.
+    pub(crate) fn receive(
+        &mut self,
+        wait_time: Duration,
+    ) -> Result<(Option<IpHeader>, Vec<u8>), Error> {
+        let (_source, ip, transport) = self.receive_from(wait_time)?;
+        Ok((ip, transport))
+    }
+
+    /// Like `receive`, but also returns the address the packet came
+    /// from, which IPv6 packets have no other way to tell.
+    pub(crate) fn receive_from(
+        &mut self,
+        wait_time: Duration,
+    ) -> Result<(IpAddr, Option<IpHeader>, Vec<u8>), Error> {
+        self.socket.set_nonblocking(true)?;
+        let now = Instant::now();
+        loop {
+            if !wait_readable(&self.socket, wait_time.saturating_sub(now.elapsed()))? {
+                return Err(Error::Timeout(wait_time));
+            }
+            // socket2 reads into uninitialised memory; ours is already
+            // initialised, which is always safe to treat as such.
+            let buf = self.buf.as_mut_slice() as *mut [u8] as *mut [MaybeUninit<u8>];
+            let buf = unsafe { &mut *buf };
+            let (bytes, source) = match self.socket.recv_from(buf) {
+                Ok(read) => read,
+                // Spurious wakeup; go back to sleep.
+                Err(ref e) if e.kind() == IoErrKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let source = match source.as_socket() {
+                Some(source) => source.ip(),
+                None => continue,
+            };
+
+            let packet = &self.buf[..bytes];
+            let (ip, transport) = match self.local {
+                SocketAddr::V4(_) => match Ipv4Header::read_from_slice(packet) {
+                    Ok((header, rest)) => (Some(IpHeader::Version4(header)), rest),
+                    Err(_) => continue,
+                },
+                SocketAddr::V6(_) => (None, packet),
+            };
+
+            // Both TCP and UDP put the destination port in bytes 2..4.
+            match transport.get(2..4) {
+                Some(port) if u16::from_be_bytes([port[0], port[1]]) == self.local.port() => {
+                    return Ok((source, ip, transport.to_vec()))
+                }
+                // Someone else's traffic.
+                _ => {}
+            }
+        }
+    }
+}
+
+/// socket2 sets `IP_HDRINCL` at the IPv6 level, which Linux reads as
+/// an unrelated option, so set `IPV6_HDRINCL` ourselves.
+#[cfg(target_os = "linux")]
+fn include_ipv6_header(socket: &Socket) -> Result<(), Error> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_HDRINCL,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn include_ipv6_header(_socket: &Socket) -> Result<(), Error> {
+    Err(Error::Unsupported("raw IPv6 sockets outside Linux"))
+}
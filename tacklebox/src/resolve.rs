@@ -0,0 +1,390 @@
+extern crate libc;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::Error as IoErr;
+use std::io::ErrorKind as IoErrKind;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::services::Services;
+use crate::Error;
+
+/// Problems found while cross-checking forward and reverse DNS,
+/// worded the way `nc -v` reports them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsWarning {
+    /// The address has no PTR record.
+    InverseLookupFailed(IpAddr),
+    /// The PTR name has no address records of its own.
+    ForwardLookupFailed(String),
+    /// The PTR name resolves, but not back to the address it came from.
+    Mismatch { host: String, name: String },
+}
+
+impl Display for DnsWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsWarning::InverseLookupFailed(ip) => {
+                write!(f, "inverse host lookup failed for {}", ip)
+            }
+            DnsWarning::ForwardLookupFailed(name) => {
+                write!(f, "forward host lookup failed for {}", name)
+            }
+            DnsWarning::Mismatch { host, name } => {
+                write!(f, "DNS fwd/rev mismatch: {} != {}", host, name)
+            }
+        }
+    }
+}
+
+/// A `host:port` target after resolution.
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub addr: SocketAddr,
+    /// Name the address was looked up by, or its PTR name for literals.
+    pub name: Option<String>,
+    pub warnings: Vec<DnsWarning>,
+}
+
+/// An `/etc/hosts`-style table used in place of the system resolver.
+#[derive(Debug, Default, Clone)]
+pub struct Hosts {
+    forward: HashMap<String, Vec<IpAddr>>,
+    reverse: HashMap<IpAddr, String>,
+}
+
+impl Hosts {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Hosts, IoErr> {
+        Hosts::from_str(&fs::read_to_string(path)?)
+    }
+
+    fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        self.forward
+            .get(&name.to_ascii_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn reverse(&self, ip: &IpAddr) -> Option<String> {
+        self.reverse.get(ip).cloned()
+    }
+}
+
+impl FromStr for Hosts {
+    type Err = IoErr;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hosts = Hosts::default();
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next().map(IpAddr::from_str) {
+                Some(Ok(ip)) => ip,
+                _ => continue,
+            };
+            for name in fields {
+                hosts.reverse.entry(ip).or_insert_with(|| name.to_string());
+                hosts
+                    .forward
+                    .entry(name.to_ascii_lowercase())
+                    .or_default()
+                    .push(ip);
+            }
+        }
+        Ok(hosts)
+    }
+}
+
+/// Turns `host:port` strings into socket addresses, checking that
+/// forward and reverse DNS agree.
+#[derive(Debug, Default, Clone)]
+pub struct Resolver {
+    numeric: bool,
+    hosts: Option<Hosts>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::default()
+    }
+
+    /// Only accept numeric addresses and skip every lookup.
+    pub fn numeric(mut self, numeric: bool) -> Resolver {
+        self.numeric = numeric;
+        self
+    }
+
+    /// Answer lookups from `hosts` instead of the system resolver.
+    pub fn hosts(mut self, hosts: Hosts) -> Resolver {
+        self.hosts = Some(hosts);
+        self
+    }
+
+    /// Resolves a `host:port`, `ip:port` or `[ipv6]:port` target. The
+    /// port may be a service name, as in `localhost:http`.
+    pub fn resolve(&self, target: &str) -> Result<Resolved, Error> {
+        let (host, port) = split_host_port(target)?;
+        self.resolve_host(host, port)
+    }
+
+    /// Resolves `host`, which may be a name or an address literal.
+    pub fn resolve_host(&self, host: &str, port: u16) -> Result<Resolved, Error> {
+        if let Ok(ip) = IpAddr::from_str(host) {
+            let mut resolved = Resolved {
+                addr: SocketAddr::new(ip, port),
+                name: None,
+                warnings: Vec::new(),
+            };
+            if !self.numeric {
+                resolved.name = self.check_reverse(ip, None, &mut resolved.warnings);
+            }
+            return Ok(resolved);
+        }
+
+        if self.numeric {
+            return Err(Error::address(host, "not a numeric address"));
+        }
+
+        let ip = self
+            .lookup(host)
+            .and_then(|addrs| {
+                addrs
+                    .first()
+                    .copied()
+                    .ok_or_else(|| IoErr::new(IoErrKind::NotFound, "no addresses found"))
+            })
+            .map_err(|source| Error::Resolve {
+                host: host.to_string(),
+                source,
+            })?;
+        let mut warnings = Vec::new();
+        self.check_reverse(ip, Some(host), &mut warnings);
+
+        Ok(Resolved {
+            addr: SocketAddr::new(ip, port),
+            name: Some(host.to_string()),
+            warnings,
+        })
+    }
+
+    /// Looks up the PTR name of `ip` and makes sure it maps back to `ip`.
+    fn check_reverse(
+        &self,
+        ip: IpAddr,
+        host: Option<&str>,
+        warnings: &mut Vec<DnsWarning>,
+    ) -> Option<String> {
+        let name = match self.reverse(ip) {
+            Some(name) => name,
+            None => {
+                warnings.push(DnsWarning::InverseLookupFailed(ip));
+                return None;
+            }
+        };
+
+        match self.lookup(&name) {
+            Ok(addrs) if addrs.contains(&ip) => {}
+            Ok(_) => warnings.push(DnsWarning::Mismatch {
+                host: host.map_or_else(|| ip.to_string(), str::to_string),
+                name: name.clone(),
+            }),
+            Err(_) => warnings.push(DnsWarning::ForwardLookupFailed(name.clone())),
+        }
+        Some(name)
+    }
+
+    fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, IoErr> {
+        if let Some(hosts) = &self.hosts {
+            return Ok(hosts.lookup(host));
+        }
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+
+    fn reverse(&self, ip: IpAddr) -> Option<String> {
+        match &self.hosts {
+            Some(hosts) => hosts.reverse(&ip),
+            None => reverse_lookup(ip),
+        }
+    }
+}
+
+/// Splits `host:port`, `ip:port` or `[ipv6]:port`.
+fn split_host_port(target: &str) -> Result<(&str, u16), Error> {
+    let invalid = || Error::address(target, "expected host:port");
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = u16::from_str(port)
+        .ok()
+        .or_else(|| Services::system().port(port, None))
+        .ok_or_else(invalid)?;
+    Ok((host, port))
+}
+
+/// Asks the system resolver for the PTR name of `ip`.
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    let addr = SocketAddr::new(ip, 0);
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: 0,
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                ..unsafe { mem::zeroed() }
+            };
+            unsafe { *(&mut storage as *mut _ as *mut libc::sockaddr_in) = sin };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                ..unsafe { mem::zeroed() }
+            };
+            unsafe { *(&mut storage as *mut _ as *mut libc::sockaddr_in6) = sin6 };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let mut name = [0 as c_char; libc::NI_MAXHOST as usize];
+    let ret = unsafe {
+        libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+            name.as_mut_ptr(),
+            name.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(hosts: &str) -> Resolver {
+        Resolver::new().hosts(Hosts::from_str(hosts).unwrap())
+    }
+
+    #[test]
+    fn forward_lookup() {
+        let resolved = resolver("10.0.0.1 box.test box\n")
+            .resolve("BOX:8080")
+            .unwrap();
+        assert_eq!(resolved.addr, "10.0.0.1:8080".parse().unwrap());
+        assert_eq!(resolved.name.as_deref(), Some("BOX"));
+        assert!(resolved.warnings.is_empty());
+    }
+
+    #[test]
+    fn literal_gets_ptr_name() {
+        let resolved = resolver("10.0.0.1 box.test\n")
+            .resolve("10.0.0.1:22")
+            .unwrap();
+        assert_eq!(resolved.name.as_deref(), Some("box.test"));
+        assert!(resolved.warnings.is_empty());
+    }
+
+    #[test]
+    fn unknown_name_fails() {
+        match resolver("10.0.0.1 box.test\n").resolve("nowhere.test:22") {
+            Err(Error::Resolve { host, .. }) => assert_eq!(host, "nowhere.test"),
+            other => panic!("expected a resolve error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_ptr_warns() {
+        let resolved = resolver("10.0.0.1 box.test\n")
+            .resolve("10.9.9.9:22")
+            .unwrap();
+        let ip: IpAddr = "10.9.9.9".parse().unwrap();
+        assert_eq!(resolved.name, None);
+        assert_eq!(resolved.warnings, vec![DnsWarning::InverseLookupFailed(ip)]);
+    }
+
+    #[test]
+    fn reverse_mismatch_warns() {
+        // A PTR record pointing at a name that resolves elsewhere, which
+        // a consistent hosts file can't express.
+        let mut hosts = Hosts::from_str("10.0.0.1 box.test\n10.0.0.2 other.test\n").unwrap();
+        hosts
+            .reverse
+            .insert("10.0.0.1".parse().unwrap(), "other.test".to_string());
+        let resolved = Resolver::new().hosts(hosts).resolve("box.test:22").unwrap();
+        assert_eq!(
+            resolved.warnings,
+            vec![DnsWarning::Mismatch {
+                host: "box.test".to_string(),
+                name: "other.test".to_string(),
+            }]
+        );
+        assert_eq!(
+            resolved.warnings[0].to_string(),
+            "DNS fwd/rev mismatch: box.test != other.test"
+        );
+    }
+
+    #[test]
+    fn numeric_rejects_names() {
+        let resolver = resolver("10.0.0.1 box.test\n").numeric(true);
+        assert!(matches!(
+            resolver.resolve("box.test:22"),
+            Err(Error::Address { .. })
+        ));
+
+        let resolved = resolver.resolve("10.0.0.1:22").unwrap();
+        assert_eq!(resolved.addr, "10.0.0.1:22".parse().unwrap());
+        assert_eq!(resolved.name, None);
+        assert!(resolved.warnings.is_empty());
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(split_host_port("box.test:22").unwrap(), ("box.test", 22));
+        assert_eq!(split_host_port("10.0.0.1:22").unwrap(), ("10.0.0.1", 22));
+        assert_eq!(split_host_port("[::1]:8080").unwrap(), ("::1", 8080));
+        assert_eq!(split_host_port("[fe80::1]:ssh").unwrap(), ("fe80::1", 22));
+        assert_eq!(
+            split_host_port("localhost:http").unwrap(),
+            ("localhost", 80)
+        );
+
+        for bad in &["box.test", "box.test:", "box.test:99999", "box.test:nosuch"] {
+            assert!(
+                matches!(split_host_port(bad), Err(Error::Address { .. })),
+                "{} should not split",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_v6_with_service_name() {
+        let resolved = resolver("::1 ip6-localhost\n")
+            .resolve("[::1]:https")
+            .unwrap();
+        assert_eq!(resolved.addr, "[::1]:443".parse().unwrap());
+        assert_eq!(resolved.name.as_deref(), Some("ip6-localhost"));
+    }
+}
@@ -0,0 +1,571 @@
+//! Port scanning: probe a list of addresses concurrently and report
+//! which ports answer.
+
+use etherparse::TcpHeader;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind as IoErrKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::raw::RawSocket;
+use crate::udp::UdpSession;
+use crate::{tcp, PacketOptions, TcpFlags};
+use crate::{Error, Protocol, Receiver, Sender, SessionBuilder};
+
+/// What a probe learned about a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortState {
+    /// Something accepted the connection.
+    Open,
+    /// The host answered, but nothing is listening.
+    Closed,
+    /// No answer before the timeout, or the host is unreachable;
+    /// usually a firewall dropping the probe.
+    Filtered,
+    /// No answer at all to a UDP probe. Many UDP services ignore
+    /// datagrams they don't understand, so silence could be either.
+    OpenFiltered,
+}
+
+impl Display for PortState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PortState::Open => write!(f, "open"),
+            PortState::Closed => write!(f, "closed"),
+            PortState::Filtered => write!(f, "filtered"),
+            PortState::OpenFiltered => write!(f, "open|filtered"),
+        }
+    }
+}
+
+/// The outcome of probing one address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortReport {
+    pub addr: SocketAddr,
+    pub state: PortState,
+    /// How long the host took to answer; `None` if it never did.
+    pub latency: Option<Duration>,
+}
+
+/// A TCP connect() scan: each port gets a full handshake, which needs
+/// no privileges but shows up in the target's logs.
+#[derive(Debug, Clone)]
+pub struct ConnectScan {
+    concurrency: usize,
+    timeout: Duration,
+}
+
+impl Default for ConnectScan {
+    fn default() -> Self {
+        ConnectScan {
+            concurrency: 100,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ConnectScan {
+    pub fn new() -> ConnectScan {
+        ConnectScan::default()
+    }
+
+    /// Most connections to have in flight at once (default 100).
+    pub fn concurrency(mut self, concurrency: usize) -> ConnectScan {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How long to wait for each connection (default 1s).
+    pub fn timeout(mut self, timeout: Duration) -> ConnectScan {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Probes one address.
+    pub fn probe(&self, addr: SocketAddr) -> Result<PortReport, Error> {
+        let now = Instant::now();
+        let (state, latency) = match TcpStream::connect_timeout(&addr, self.timeout) {
+            Ok(_) => (PortState::Open, Some(now.elapsed())),
+            Err(e) => match e.kind() {
+                IoErrKind::ConnectionRefused => (PortState::Closed, Some(now.elapsed())),
+                // Dropped, rejected by a firewall (which Linux reports
+                // as EPERM or EACCES), or reset before the handshake
+                // finished: this port's business, not the scan's.
+                IoErrKind::TimedOut
+                | IoErrKind::WouldBlock
+                | IoErrKind::HostUnreachable
+                | IoErrKind::NetworkUnreachable
+                | IoErrKind::PermissionDenied
+                | IoErrKind::AddrNotAvailable
+                | IoErrKind::ConnectionReset => (PortState::Filtered, None),
+                // Out of descriptors and the like, which every other
+                // probe would hit too.
+                _ => return Err(Error::Connect { addr, source: e }),
+            },
+        };
+        Ok(PortReport {
+            addr,
+            state,
+            latency,
+        })
+    }
+
+    /// Probes every address in `targets`, in that order but up to
+    /// `concurrency` at a time. Reports come back in the same order.
+    pub fn run(&self, targets: &[SocketAddr]) -> Result<Vec<PortReport>, Error> {
+        run(self.concurrency, targets, |addr| self.probe(addr))
+    }
+}
+
+/// A UDP scan. Each port is sent a datagram from a connected socket:
+/// a reply means `Open`, ICMP port unreachable means `Closed`, other
+/// unreachables mean `Filtered`, and silence after every retry is
+/// `OpenFiltered`. No root is needed.
+///
+/// Hosts rate-limit their ICMP errors, so scans that go too fast see
+/// closed ports as open|filtered; `rate` and `retries` trade speed for
+/// accuracy.
+#[derive(Debug, Clone)]
+pub struct UdpScan {
+    concurrency: usize,
+    timeout: Duration,
+    retries: u32,
+    rate: Option<u32>,
+    payload: Vec<u8>,
+}
+
+impl Default for UdpScan {
+    fn default() -> Self {
+        UdpScan {
+            concurrency: 100,
+            timeout: Duration::from_secs(1),
+            retries: 2,
+            rate: None,
+            payload: Vec::new(),
+        }
+    }
+}
+
+impl UdpScan {
+    pub fn new() -> UdpScan {
+        UdpScan::default()
+    }
+
+    /// Most ports to probe at once (default 100).
+    pub fn concurrency(mut self, concurrency: usize) -> UdpScan {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How long to wait for an answer to each datagram (default 1s).
+    pub fn timeout(mut self, timeout: Duration) -> UdpScan {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more times to send to a silent port (default 2).
+    pub fn retries(mut self, retries: u32) -> UdpScan {
+        self.retries = retries;
+        self
+    }
+
+    /// Most datagrams to send per second across the whole scan.
+    pub fn rate(mut self, rate: Option<u32>) -> UdpScan {
+        self.rate = rate.filter(|&rate| rate > 0);
+        self
+    }
+
+    /// What to send; empty by default.
+    pub fn payload(mut self, payload: &[u8]) -> UdpScan {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Probes one address, waiting on `limit` before every send.
+    fn probe(&self, addr: SocketAddr, limit: &RateLimit) -> Result<PortReport, Error> {
+        let mut session: UdpSession = SessionBuilder::new()
+            .local(SocketAddr::new(unspecified(addr), 0))
+            .udp()?;
+        session.connect(addr)?;
+
+        let report = |state, latency| PortReport {
+            addr,
+            state,
+            latency,
+        };
+        for _ in 0..=self.retries {
+            limit.wait();
+            let now = Instant::now();
+            let answer = match session.send(&self.payload) {
+                Ok(_) => session.receive(self.timeout).map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match answer {
+                Ok(()) => return Ok(report(PortState::Open, Some(now.elapsed()))),
+                Err(Error::Connect { source, .. }) => {
+                    let state = match source.kind() {
+                        IoErrKind::ConnectionRefused => PortState::Closed,
+                        _ => PortState::Filtered,
+                    };
+                    return Ok(report(state, Some(now.elapsed())));
+                }
+                Err(Error::Timeout(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report(PortState::OpenFiltered, None))
+    }
+
+    /// Probes every address in `targets`, in that order but up to
+    /// `concurrency` at a time. Reports come back in the same order.
+    pub fn run(&self, targets: &[SocketAddr]) -> Result<Vec<PortReport>, Error> {
+        let limit = RateLimit::new(self.rate);
+        run(self.concurrency, targets, |addr| self.probe(addr, &limit))
+    }
+}
+
+/// A SYN, or half-open, scan: each port is sent a bare SYN from a raw
+/// socket and never gets the final ACK. A SYN/ACK means `Open`, a RST
+/// means `Closed` and silence after every retry is `Filtered`; the
+/// kernel answers the SYN/ACK with a RST of its own, since no socket
+/// of ours owns the port. Needs root or `CAP_NET_RAW`.
+///
+/// Every SYN goes out from one thread and every reply is read by one
+/// loop, so the whole range is in flight at once. Replies are matched
+/// to probes by their acknowledgment number, which has to be one past
+/// a sequence number derived from the probe's address and a secret.
+#[derive(Debug, Clone)]
+pub struct SynScan {
+    timeout: Duration,
+    retries: u32,
+    rate: Option<u32>,
+}
+
+impl Default for SynScan {
+    fn default() -> Self {
+        SynScan {
+            timeout: Duration::from_secs(1),
+            retries: 1,
+            rate: None,
+        }
+    }
+}
+
+impl SynScan {
+    pub fn new() -> SynScan {
+        SynScan::default()
+    }
+
+    /// How long to wait for replies after the last SYN (default 1s).
+    pub fn timeout(mut self, timeout: Duration) -> SynScan {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many more rounds of SYNs to send to silent ports (default 1).
+    pub fn retries(mut self, retries: u32) -> SynScan {
+        self.retries = retries;
+        self
+    }
+
+    /// Most SYNs to send per second.
+    pub fn rate(mut self, rate: Option<u32>) -> SynScan {
+        self.rate = rate.filter(|&rate| rate > 0);
+        self
+    }
+
+    /// Probes every address in `targets`, in that order. Reports come
+    /// back in the same order.
+    pub fn run(&self, targets: &[SocketAddr]) -> Result<Vec<PortReport>, Error> {
+        let mut reports = vec![None; targets.len()];
+        // A raw socket only speaks one IP version.
+        for v4 in [true, false] {
+            let probes: Vec<(usize, SocketAddr)> = targets
+                .iter()
+                .copied()
+                .enumerate()
+                .filter(|(_, addr)| addr.is_ipv4() == v4)
+                .collect();
+            if !probes.is_empty() {
+                self.run_family(&probes, &mut reports)?;
+            }
+        }
+
+        Ok(reports
+            .into_iter()
+            .zip(targets)
+            .map(|(report, &addr)| {
+                report.unwrap_or(PortReport {
+                    addr,
+                    state: PortState::Filtered,
+                    latency: None,
+                })
+            })
+            .collect())
+    }
+
+    /// Scans `probes`, which are all one IP version, filling in
+    /// `reports` for the ports that answer.
+    fn run_family(
+        &self,
+        probes: &[(usize, SocketAddr)],
+        reports: &mut [Option<PortReport>],
+    ) -> Result<(), Error> {
+        // Replies come back to our port, which no socket owns.
+        let local = crate::random_port(unspecified(probes[0].1));
+        let mut receiver = RawSocket::new(local, Protocol::Tcp)?;
+        let sender = RawSocket::new(local, Protocol::Tcp)?;
+
+        let secret: u64 = rand::random();
+        let index: HashMap<SocketAddr, usize> = probes.iter().map(|&(i, addr)| (addr, i)).collect();
+        let mut sources = HashMap::new();
+        let mut packets = Vec::with_capacity(probes.len());
+        for &(i, addr) in probes {
+            let source = match sources.get(&addr.ip()) {
+                Some(&source) => source,
+                None => {
+                    let source = route_source(addr)?;
+                    sources.insert(addr.ip(), source);
+                    source
+                }
+            };
+            let options = PacketOptions {
+                flags: TcpFlags {
+                    syn: true,
+                    ..TcpFlags::default()
+                },
+                sequence_number: cookie(secret, addr),
+                window_size: 1024,
+                ..PacketOptions::default()
+            };
+            let from = SocketAddr::new(source, local.port());
+            packets.push((i, tcp::encode(from, addr, &options, &[])?));
+        }
+
+        let limit = RateLimit::new(self.rate);
+        let sent_at = Mutex::new(vec![None; reports.len()]);
+        for _ in 0..=self.retries {
+            let pending: Vec<&(usize, Vec<u8>)> = packets
+                .iter()
+                .filter(|(i, _)| reports[*i].is_none())
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            let finished = Mutex::new(None);
+
+            thread::scope(|scope| -> Result<(), Error> {
+                let sending = scope.spawn(|| -> Result<(), Error> {
+                    let sent = pending.iter().try_for_each(|(i, packet)| {
+                        limit.wait();
+                        sent_at.lock().expect("scan sender panicked")[*i] = Some(Instant::now());
+                        sender.send(packet).map(|_| ())
+                    });
+                    *finished.lock().expect("scan sender panicked") = Some(Instant::now());
+                    sent
+                });
+
+                let mut answered = 0;
+                while answered < pending.len() {
+                    let finished = *finished.lock().expect("scan sender panicked");
+                    let wait = match finished {
+                        Some(finished) => {
+                            // A timeout too far off to represent never ends.
+                            let left = finished
+                                .checked_add(self.timeout)
+                                .map_or(RECEIVE_SLICE, |deadline| {
+                                    deadline.saturating_duration_since(Instant::now())
+                                });
+                            if left.is_zero() {
+                                break;
+                            }
+                            left.min(RECEIVE_SLICE)
+                        }
+                        None => RECEIVE_SLICE,
+                    };
+                    let (source, _ip, segment) = match receiver.receive_from(wait) {
+                        Ok(received) => received,
+                        Err(Error::Timeout(_)) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let header = match TcpHeader::read_from_slice(&segment) {
+                        Ok((header, _)) => header,
+                        Err(_) => continue,
+                    };
+
+                    let addr = SocketAddr::new(source, header.source_port);
+                    let i = match index.get(&addr) {
+                        Some(&i) if reports[i].is_none() => i,
+                        _ => continue,
+                    };
+                    if header.acknowledgment_number != cookie(secret, addr).wrapping_add(1) {
+                        continue;
+                    }
+                    let state = match (header.syn, header.ack, header.rst) {
+                        (true, true, _) => PortState::Open,
+                        (_, _, true) => PortState::Closed,
+                        _ => continue,
+                    };
+                    let sent = sent_at.lock().expect("scan sender panicked")[i];
+                    reports[i] = Some(PortReport {
+                        addr,
+                        state,
+                        latency: sent.map(|sent| sent.elapsed()),
+                    });
+                    answered += 1;
+                }
+
+                sending.join().expect("scan sender panicked")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// How long the receive loop sleeps before checking whether the
+/// sender is done.
+const RECEIVE_SLICE: Duration = Duration::from_millis(50);
+
+/// The sequence number a SYN to `addr` carries.
+fn cookie(secret: u64, addr: SocketAddr) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    addr.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// The local address the kernel would send to `addr` from, which the
+/// TCP checksum has to cover. Connecting a UDP socket sends nothing.
+fn route_source(addr: SocketAddr) -> Result<IpAddr, Error> {
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified(addr), 0))?;
+    socket
+        .connect(addr)
+        .map_err(|source| Error::Connect { addr, source })?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The wildcard address of `addr`'s IP version.
+fn unspecified(addr: SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Spaces sends evenly so no more than `rate` go out per second,
+/// however many threads are sending.
+struct RateLimit {
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn new(rate: Option<u32>) -> RateLimit {
+        RateLimit {
+            interval: rate.map(|rate| Duration::from_secs(1) / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Sleeps until the caller's turn to send.
+    fn wait(&self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let turn = {
+            let mut next = self.next.lock().expect("rate limiter poisoned");
+            let turn = (*next).max(Instant::now());
+            *next = turn + interval;
+            turn
+        };
+        thread::sleep(turn.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Runs `probe` on every address in `targets`, `concurrency` at a time,
+/// keeping the reports in the order of `targets`. The first error a
+/// probe returns ends the run.
+pub fn run<T, F>(concurrency: usize, targets: &[SocketAddr], probe: F) -> Result<Vec<T>, Error>
+where
+    T: Send,
+    F: Fn(SocketAddr) -> Result<T, Error> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(targets.iter().map(|_| None).collect::<Vec<Option<T>>>());
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency.min(targets.len()))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), Error> {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let addr = match targets.get(i) {
+                            Some(addr) => *addr,
+                            None => return Ok(()),
+                        };
+                        let report = probe(addr)?;
+                        reports.lock().expect("scan worker panicked")[i] = Some(report);
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("scan worker panicked"))
+    })?;
+
+    Ok(reports
+        .into_inner()
+        .expect("scan worker panicked")
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_caps_probes_in_flight() {
+        let targets: Vec<SocketAddr> = (1..=40)
+            .map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .collect();
+        let in_flight = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+
+        let ports = run(4, &targets, |addr| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(addr.port())
+        })
+        .unwrap();
+
+        assert_eq!(ports, (1..=40).collect::<Vec<u16>>());
+        assert!(most.load(Ordering::SeqCst) <= 4);
+        assert!(most.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn run_stops_on_a_probe_error() {
+        let targets: Vec<SocketAddr> = (1..=10)
+            .map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .collect();
+        let result = run(2, &targets, |addr| match addr.port() {
+            5 => Err(Error::NoRemote),
+            port => Ok(port),
+        });
+        assert!(matches!(result, Err(Error::NoRemote)));
+    }
+}
@@ -0,0 +1,150 @@
+//! Service names for port numbers, from `/etc/services`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::{Error, Protocol};
+
+/// Used when the system has no `/etc/services`.
+const EMBEDDED: &str = include_str!("services.txt");
+
+/// A lookup table between ports and the names of the services that
+/// usually run on them, in both directions.
+#[derive(Debug, Default, Clone)]
+pub struct Services {
+    by_port: HashMap<(u16, Protocol), String>,
+    by_name: HashMap<String, Vec<(u16, Protocol)>>,
+}
+
+impl Services {
+    /// Parses `/etc/services` syntax: `name port/protocol [aliases...]`,
+    /// with `#` comments. Lines that don't fit, or name protocols other
+    /// than TCP and UDP, are skipped.
+    pub fn parse(text: &str) -> Services {
+        let mut services = Services::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let (name, entry) = match (fields.next(), fields.next()) {
+                (Some(name), Some(entry)) => (name, entry),
+                _ => continue,
+            };
+            let (port, protocol) = match entry.split_once('/') {
+                Some((port, protocol)) => (port, protocol),
+                None => continue,
+            };
+            let (port, protocol) = match (port.parse::<u16>(), protocol.parse::<Protocol>()) {
+                (Ok(port), Ok(protocol)) => (port, protocol),
+                _ => continue,
+            };
+
+            // The first name listed for a port is the one it goes by.
+            services
+                .by_port
+                .entry((port, protocol))
+                .or_insert_with(|| name.to_string());
+            for name in std::iter::once(name).chain(fields) {
+                services
+                    .by_name
+                    .entry(name.to_ascii_lowercase())
+                    .or_default()
+                    .push((port, protocol));
+            }
+        }
+        services
+    }
+
+    /// Reads a services file.
+    pub fn load(path: &Path) -> Result<Services, Error> {
+        Ok(Services::parse(&fs::read_to_string(path)?))
+    }
+
+    /// The copy built into tacklebox.
+    pub fn embedded() -> Services {
+        Services::parse(EMBEDDED)
+    }
+
+    /// The system's `/etc/services`, or the embedded copy if it can't
+    /// be read. Loaded once, on first use.
+    pub fn system() -> &'static Services {
+        static SYSTEM: OnceLock<Services> = OnceLock::new();
+        SYSTEM.get_or_init(|| {
+            Services::load(Path::new("/etc/services")).unwrap_or_else(|_| Services::embedded())
+        })
+    }
+
+    /// The name of the service on `port`, e.g. `ssh` for 22/tcp.
+    pub fn name(&self, port: u16, protocol: Protocol) -> Option<&str> {
+        self.by_port.get(&(port, protocol)).map(String::as_str)
+    }
+
+    /// The port a service name or alias stands for, on `protocol` if
+    /// given. Names are matched case-insensitively.
+    pub fn port(&self, name: &str, protocol: Option<Protocol>) -> Option<u16> {
+        let entries = self.by_name.get(&name.to_ascii_lowercase())?;
+        entries
+            .iter()
+            .find(|(_, entry)| protocol.is_none_or(|protocol| *entry == protocol))
+            .map(|(port, _)| *port)
+    }
+
+    /// `port` as `22/tcp ssh`, or `4800/udp` if it has no name.
+    pub fn label(&self, port: u16, protocol: Protocol) -> String {
+        match self.name(port, protocol) {
+            Some(name) => format!("{}/{} {}", port, protocol, name),
+            None => format!("{}/{}", port, protocol),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICES: &str = "\
+# Network services, Internet style
+ssh\t\t22/tcp\t\t\t\t# SSH Remote Login Protocol
+smtp\t\t25/tcp\t\tmail
+domain\t\t53/tcp
+domain\t\t53/udp
+http\t\t80/tcp\t\twww\t\t# WorldWideWeb HTTP
+syslog\t\t514/udp
+shell\t\t514/tcp\t\tcmd
+ddp\t\t2/ddp
+broken\t\t80
+";
+
+    #[test]
+    fn names_to_ports() {
+        let services = Services::parse(SERVICES);
+        assert_eq!(services.port("ssh", None), Some(22));
+        assert_eq!(services.port("WWW", Some(Protocol::Tcp)), Some(80));
+        assert_eq!(services.port("mail", None), Some(25));
+        assert_eq!(services.port("domain", Some(Protocol::Udp)), Some(53));
+        assert_eq!(services.port("syslog", Some(Protocol::Udp)), Some(514));
+        assert_eq!(services.port("syslog", Some(Protocol::Tcp)), None);
+        assert_eq!(services.port("ddp", None), None);
+        assert_eq!(services.port("broken", None), None);
+        assert_eq!(services.port("gopher", None), None);
+    }
+
+    #[test]
+    fn ports_to_labels() {
+        let services = Services::parse(SERVICES);
+        assert_eq!(services.name(25, Protocol::Tcp), Some("smtp"));
+        assert_eq!(services.label(22, Protocol::Tcp), "22/tcp ssh");
+        assert_eq!(services.label(514, Protocol::Tcp), "514/tcp shell");
+        assert_eq!(services.label(514, Protocol::Udp), "514/udp syslog");
+        assert_eq!(services.label(22, Protocol::Udp), "22/udp");
+        assert_eq!(services.label(4800, Protocol::Tcp), "4800/tcp");
+    }
+
+    #[test]
+    fn embedded_copy_parses() {
+        let services = Services::embedded();
+        assert_eq!(services.port("https", Some(Protocol::Tcp)), Some(443));
+        assert_eq!(services.name(53, Protocol::Udp), Some("domain"));
+    }
+}
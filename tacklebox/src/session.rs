@@ -0,0 +1,352 @@
+extern crate socket2;
+use socket2::{Domain, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use crate::aio::{AsyncTcpSession, AsyncUdpSession};
+use crate::packet::PacketOptions;
+use crate::tcp::TcpSession;
+use crate::udp::UdpSession;
+use crate::{random_local, Error, Session};
+
+/// Socket options applied to every socket a session opens.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SocketOptions {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) reuse_address: Option<bool>,
+    pub(crate) broadcast: bool,
+    pub(crate) recv_buffer_size: Option<usize>,
+    pub(crate) send_buffer_size: Option<usize>,
+    pub(crate) gso: bool,
+    pub(crate) gro: bool,
+    pub(crate) raw: bool,
+    pub(crate) packet: PacketOptions,
+}
+
+impl SocketOptions {
+    /// `addr` is the address `socket` will be bound or connected to.
+    fn apply(&self, socket: &Socket, addr: SocketAddr) -> Result<(), Error> {
+        socket.set_write_timeout(self.write_timeout)?;
+        self.packet.apply(socket, addr)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Opens a socket of `kind` bound to `local`.
+    ///
+    /// Listeners reuse their address unless told otherwise, the same as
+    /// `TcpListener::bind` does on Unix.
+    fn bind(&self, local: SocketAddr, kind: Type) -> Result<Socket, Error> {
+        let socket = Socket::new(Domain::for_address(local), kind, None)?;
+        self.apply(&socket, local)?;
+        socket.set_reuse_address(self.reuse_address.unwrap_or(kind == Type::STREAM))?;
+        socket.bind(&local.into()).map_err(|source| Error::Bind {
+            addr: local,
+            source,
+        })?;
+        Ok(socket)
+    }
+
+    pub(crate) fn tcp_listener(&self, local: SocketAddr) -> Result<TcpListener, Error> {
+        let socket = self.bind(local, Type::STREAM)?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    }
+
+    pub(crate) fn tcp_stream(&self, remote: SocketAddr) -> Result<TcpStream, Error> {
+        let socket = Socket::new(Domain::for_address(remote), Type::STREAM, None)?;
+        self.apply(&socket, remote)?;
+        if let Some(reuse) = self.reuse_address {
+            socket.set_reuse_address(reuse)?;
+        }
+        let connected = match self.connect_timeout {
+            Some(timeout) => socket.connect_timeout(&remote.into(), timeout),
+            None => socket.connect(&remote.into()),
+        };
+        connected.map_err(|source| Error::Connect {
+            addr: remote,
+            source,
+        })?;
+
+        let stream: TcpStream = socket.into();
+        self.configure_stream(&stream)?;
+        Ok(stream)
+    }
+
+    /// Applies the per-connection options to an accepted or connected stream.
+    pub(crate) fn configure_stream(&self, stream: &TcpStream) -> Result<(), Error> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        self.packet.apply(stream, stream.local_addr()?)?;
+        Ok(())
+    }
+
+    pub(crate) fn udp_socket(&self, local: SocketAddr) -> Result<UdpSocket, Error> {
+        let socket = self.bind(local, Type::DGRAM)?;
+        socket.set_broadcast(self.broadcast)?;
+        let socket: UdpSocket = socket.into();
+        if self.gso && !cfg!(target_os = "linux") {
+            return Err(Error::Unsupported("UDP segmentation offload outside Linux"));
+        }
+        if self.gro {
+            #[cfg(target_os = "linux")]
+            crate::batch::enable_gro(&socket)?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(Error::Unsupported("UDP receive offload outside Linux"));
+        }
+        Ok(socket)
+    }
+}
+
+/// Builds `TcpSession`s and `UdpSession`s.
+///
+/// If no local address is given, a random port on the loopback
+/// address of the remote's IP version is used.
+#[derive(Debug, Default, Clone)]
+pub struct SessionBuilder {
+    local: Option<SocketAddr>,
+    remote: Option<SocketAddr>,
+    options: SocketOptions,
+}
+
+impl SessionBuilder {
+    pub fn new() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    pub fn local(mut self, local: SocketAddr) -> SessionBuilder {
+        self.local = Some(local);
+        self
+    }
+
+    /// For TCP, the session connects to `remote` when built.
+    pub fn remote(mut self, remote: SocketAddr) -> SessionBuilder {
+        self.remote = Some(remote);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.options.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> SessionBuilder {
+        self.options.packet.ttl = Some(ttl);
+        self
+    }
+
+    /// Header fields for the packets the session sends; see `PacketOptions`.
+    /// Replaces any TTL set with `ttl`.
+    pub fn packet_options(mut self, packet: PacketOptions) -> SessionBuilder {
+        self.options.packet = packet;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> SessionBuilder {
+        self.options.nodelay = nodelay;
+        self
+    }
+
+    /// Sets `SO_REUSEADDR` on every socket the session opens, including
+    /// outgoing connections, whose port can then be bound again while
+    /// it lingers in `TIME_WAIT`. Listeners reuse their address unless
+    /// this is turned off.
+    pub fn reuse_address(mut self, reuse: bool) -> SessionBuilder {
+        self.options.reuse_address = Some(reuse);
+        self
+    }
+
+    pub fn broadcast(mut self, broadcast: bool) -> SessionBuilder {
+        self.options.broadcast = broadcast;
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> SessionBuilder {
+        self.options.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> SessionBuilder {
+        self.options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Lets the kernel split batched UDP sends into datagrams itself
+    /// (`UDP_SEGMENT`). Plain UDP sessions on Linux only; not for raw
+    /// or async ones.
+    pub fn gso(mut self, gso: bool) -> SessionBuilder {
+        self.options.gso = gso;
+        self
+    }
+
+    /// Lets the kernel coalesce received UDP datagrams into larger reads
+    /// (`UDP_GRO`); sessions split them back apart. Plain UDP sessions
+    /// on Linux only; not for raw or async ones.
+    pub fn gro(mut self, gro: bool) -> SessionBuilder {
+        self.options.gro = gro;
+        self
+    }
+
+    /// Sends crafted IP packets on a raw socket and decodes the headers
+    /// of what comes back, instead of sending the payload alone. Needs
+    /// root or `CAP_NET_RAW`.
+    pub fn raw(mut self, raw: bool) -> SessionBuilder {
+        self.options.raw = raw;
+        self
+    }
+
+    pub fn build<S: Session>(&self) -> Result<S, Error> {
+        S::build(self)
+    }
+
+    pub fn tcp(&self) -> Result<TcpSession, Error> {
+        let mut session = TcpSession::with_options(self.local_addr(), self.options.clone())?;
+        if let Some(remote) = self.remote {
+            session.connect_to(remote)?;
+        }
+        Ok(session)
+    }
+
+    pub fn udp(&self) -> Result<UdpSession, Error> {
+        let mut session = UdpSession::with_options(self.local_addr(), self.options.clone())?;
+        if let Some(remote) = self.remote {
+            session.set_remote(remote);
+        }
+        Ok(session)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn tcp_async(&self) -> Result<AsyncTcpSession, Error> {
+        let mut session = AsyncTcpSession::with_options(self.local_addr(), self.options.clone())?;
+        if let Some(remote) = self.remote {
+            session.connect_to(remote).await?;
+        }
+        Ok(session)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn udp_async(&self) -> Result<AsyncUdpSession, Error> {
+        let mut session = AsyncUdpSession::with_options(self.local_addr(), self.options.clone())?;
+        if let Some(remote) = self.remote {
+            session.set_remote(remote);
+        }
+        Ok(session)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        match (self.local, self.remote) {
+            (Some(local), _) => local,
+            (None, Some(remote)) => random_local(remote.ip()),
+            (None, None) => random_local(Ipv4Addr::LOCALHOST.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+
+    fn unsupported<T>(result: Result<T, Error>) {
+        match result {
+            Err(Error::Unsupported(_)) => {}
+            Err(e) => panic!("expected Error::Unsupported, got {:?}", e),
+            Ok(_) => panic!("expected Error::Unsupported, got a session"),
+        }
+    }
+
+    #[test]
+    fn tcp_options_reach_the_socket() {
+        let builder = SessionBuilder::new()
+            .nodelay(true)
+            .ttl(7)
+            .write_timeout(Duration::from_secs(3))
+            .recv_buffer_size(64 * 1024);
+        let listener = builder
+            .options
+            .tcp_listener((Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap();
+        // Listeners reuse their address unless told not to.
+        assert!(SockRef::from(&listener).reuse_address().unwrap());
+        assert_eq!(listener.ttl().unwrap(), 7);
+
+        let stream = builder
+            .reuse_address(true)
+            .options
+            .tcp_stream(listener.local_addr().unwrap())
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.ttl().unwrap(), 7);
+        assert_eq!(
+            stream.write_timeout().unwrap(),
+            Some(Duration::from_secs(3))
+        );
+        let socket = SockRef::from(&stream);
+        assert!(socket.reuse_address().unwrap());
+        // Linux doubles the size asked for, to leave room for overhead.
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let listener = SessionBuilder::new()
+            .reuse_address(false)
+            .options
+            .tcp_listener((Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap();
+        assert!(!SockRef::from(&listener).reuse_address().unwrap());
+        let stream = SessionBuilder::new()
+            .options
+            .tcp_stream(listener.local_addr().unwrap())
+            .unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[test]
+    fn udp_options_reach_the_socket() {
+        let socket = SessionBuilder::new()
+            .ttl(9)
+            .broadcast(true)
+            .reuse_address(true)
+            .options
+            .udp_socket((Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap();
+        assert_eq!(socket.ttl().unwrap(), 9);
+        assert!(socket.broadcast().unwrap());
+        assert!(SockRef::from(&socket).reuse_address().unwrap());
+
+        let socket = SessionBuilder::new()
+            .options
+            .udp_socket((Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap();
+        assert!(!socket.broadcast().unwrap());
+        assert!(!SockRef::from(&socket).reuse_address().unwrap());
+    }
+
+    #[test]
+    fn offload_is_rejected_where_unsupported() {
+        let local: SocketAddr = (Ipv4Addr::LOCALHOST, 0).into();
+        let builder = SessionBuilder::new().local(local);
+        unsupported(builder.clone().gso(true).tcp());
+        unsupported(builder.clone().gro(true).tcp());
+        // Checked before the raw socket is opened, so this needs no
+        // privileges.
+        unsupported(builder.clone().raw(true).gso(true).udp());
+        unsupported(builder.clone().raw(true).gro(true).udp());
+
+        #[cfg(target_os = "linux")]
+        builder.gso(true).gro(true).udp().unwrap();
+        #[cfg(not(target_os = "linux"))]
+        unsupported(builder.gro(true).udp());
+    }
+}
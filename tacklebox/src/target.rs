@@ -0,0 +1,288 @@
+//! Port sets and `host:ports` targets, written the way scanners take
+//! them: `example.com:20-25,80,443,8000-8100`.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::resolve::{Resolved, Resolver};
+use crate::services::Services;
+use crate::Error;
+
+/// A set of ports such as `20-25,80,443,8000-8100`. Service names
+/// from `/etc/services` can stand in for numbers: `ssh,http-alt`.
+///
+/// Overlapping and adjacent ranges are merged, so every port is
+/// visited once and in ascending order by `iter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortRange {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortRange {
+    pub fn single(port: u16) -> PortRange {
+        PortRange {
+            ranges: vec![port..=port],
+        }
+    }
+
+    /// How many ports are in the set.
+    pub fn len(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| (*range.end() - *range.start()) as usize + 1)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+
+    /// The merged ranges, in ascending order.
+    pub fn ranges(&self) -> &[RangeInclusive<u16>] {
+        &self.ranges
+    }
+
+    /// The lowest port in the set.
+    pub fn first(&self) -> Option<u16> {
+        self.ranges.first().map(|range| *range.start())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().flat_map(Clone::clone)
+    }
+
+    /// Every port in the set, in an order drawn from `rng`.
+    pub fn shuffled<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.iter().collect();
+        ports.shuffle(rng);
+        ports
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Error::address(s, reason);
+        // A number, or a service name from `/etc/services`.
+        let port = |port: &str| {
+            let port = port.trim();
+            port.parse::<u16>()
+                .ok()
+                .or_else(|| Services::system().port(port, None))
+                .ok_or_else(|| invalid("expected ports like 20-25,80,443 or service names"))
+        };
+
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            // Service names such as `http-alt` have dashes of their own,
+            // so try the whole part first, then every dash in turn.
+            let range = match port(part) {
+                Ok(port) => port..=port,
+                Err(e) => part
+                    .match_indices('-')
+                    .find_map(|(dash, _)| {
+                        let start = port(&part[..dash]).ok()?;
+                        let end = port(&part[dash + 1..]).ok()?;
+                        Some(start..=end)
+                    })
+                    .ok_or(e)?,
+            };
+            if range.is_empty() {
+                return Err(invalid("port range ends before it starts"));
+            }
+            ranges.push(range);
+        }
+
+        ranges.sort_by_key(|range| *range.start());
+        let mut merged: Vec<RangeInclusive<u16>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if *range.start() as u32 <= *last.end() as u32 + 1 => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+        Ok(PortRange { ranges: merged })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A host and the ports to try on it: `host:PORTS`, `ip:PORTS` or
+/// `[ipv6]:PORTS`, where `PORTS` is a `PortRange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetSpec {
+    pub host: String,
+    pub ports: PortRange,
+}
+
+impl TargetSpec {
+    /// Resolves the host; the address carries the lowest port.
+    pub fn resolve(&self, resolver: &Resolver) -> Result<Resolved, Error> {
+        resolver.resolve_host(&self.host, self.ports.first().unwrap_or(0))
+    }
+
+    /// The single port this spec names, for places that take just one.
+    pub fn single_port(&self) -> Result<u16, Error> {
+        match self.ports.ranges() {
+            [range] if range.start() == range.end() => Ok(*range.start()),
+            _ => Err(Error::address(&self.to_string(), "expected a single port")),
+        }
+    }
+
+    /// An address on `ip` for every port, in ascending order.
+    pub fn addrs(&self, ip: IpAddr) -> Vec<SocketAddr> {
+        self.ports
+            .iter()
+            .map(|port| SocketAddr::new(ip, port))
+            .collect()
+    }
+}
+
+impl FromStr for TargetSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ports) = s
+            .rsplit_once(':')
+            .ok_or_else(|| Error::address(s, "expected host:ports"))?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(Error::address(s, "expected host:ports"));
+        }
+        Ok(TargetSpec {
+            host: host.to_string(),
+            ports: ports.parse()?,
+        })
+    }
+}
+
+impl Display for TargetSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.ports)
+        } else {
+            write!(f, "{}:{}", self.host, self.ports)
+        }
+    }
+}
+
+/// A generator for `PortRange::shuffled`: seeded if `seed` is given,
+/// so an order can be repeated, or from the OS otherwise.
+pub fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(s: &str) -> Vec<u16> {
+        s.parse::<PortRange>().unwrap().iter().collect()
+    }
+
+    fn rejects(s: &str) {
+        match s.parse::<PortRange>() {
+            Err(Error::Address { addr, .. }) => assert_eq!(addr, s),
+            other => panic!("expected {:?} to be rejected, got {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn ranges_lists_and_names() {
+        assert_eq!(ports("22-25,80"), [22, 23, 24, 25, 80]);
+        assert_eq!(ports("443"), [443]);
+        assert_eq!(ports("http"), [80]);
+        assert_eq!(ports("ssh-smtp, http-alt"), [22, 23, 24, 25, 8080]);
+        assert_eq!(ports("0,65535"), [0, 65535]);
+        assert_eq!("8000-8100".parse::<PortRange>().unwrap().len(), 101);
+    }
+
+    #[test]
+    fn invalid_ports_are_rejected() {
+        rejects("");
+        rejects("80,");
+        rejects("65536");
+        rejects("1-65536");
+        rejects("25-22");
+        rejects("nosuchservice");
+        rejects("-5");
+    }
+
+    #[test]
+    fn overlapping_ranges_merge() {
+        let range: PortRange = "30-40,80,20-35,41,79".parse().unwrap();
+        assert_eq!(range.ranges(), [20..=41, 79..=80]);
+        assert_eq!(range.to_string(), "20-41,79-80");
+        assert_eq!(range.len(), 24);
+        assert_eq!(range.first(), Some(20));
+        assert!(range.contains(41) && !range.contains(42));
+    }
+
+    #[test]
+    fn seeded_shuffle_repeats() {
+        let range: PortRange = "1-100".parse().unwrap();
+        let order = range.shuffled(&mut rng(Some(7)));
+        assert_eq!(order, range.shuffled(&mut rng(Some(7))));
+        assert_ne!(order, range.shuffled(&mut rng(Some(8))));
+        assert_ne!(order, range.iter().collect::<Vec<_>>());
+
+        let mut sorted = order;
+        sorted.sort_unstable();
+        assert_eq!(sorted, range.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn target_specs() {
+        let spec: TargetSpec = "example.com:20-25,80".parse().unwrap();
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.ports, "20-25,80".parse().unwrap());
+        assert!(spec.single_port().is_err());
+
+        let spec: TargetSpec = "[::1]:22".parse().unwrap();
+        assert_eq!(spec.host, "::1");
+        assert_eq!(spec.single_port().unwrap(), 22);
+        assert_eq!(spec.to_string(), "[::1]:22");
+        assert_eq!(
+            spec.addrs("::1".parse().unwrap()),
+            ["[::1]:22".parse().unwrap()]
+        );
+
+        assert!("example.com".parse::<TargetSpec>().is_err());
+        assert!(":80".parse::<TargetSpec>().is_err());
+        assert!("example.com:99999".parse::<TargetSpec>().is_err());
+    }
+}
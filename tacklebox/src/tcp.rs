@@ -1,144 +1,351 @@
 extern crate etherparse;
-use etherparse::PacketBuilder;
-use etherparse::TcpHeader;
-use std::io::{Error, ErrorKind, Read};
-use std::net::{TcpListener, TcpStream};
-use std::net::Ipv4Addr;
-use std::time::Instant;
+use etherparse::{IpHeader, TcpHeader};
 use std::io::Write;
-use std::str::FromStr;
+use std::io::{ErrorKind, Read};
+use std::net::SocketAddr;
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
 
-use crate::Sender;
+use crate::packet::PacketOptions;
+use crate::poll::wait_readable;
+use crate::raw::RawSocket;
+use crate::session::SocketOptions;
+use crate::{Datagram, Error, Protocol, Receiver, Sender, Session, SessionBuilder};
 
-use Error as IoErr;
 use ErrorKind as IoErrKind;
 
-#[derive(Default, Debug, Clone)]
+/// What a single read returned. The headers are only there for
+/// packets read off a raw socket; see `SessionBuilder::raw`.
+#[derive(Debug, Clone)]
 pub struct TcpDatagram {
-    pub header: TcpHeader,
+    pub ip: Option<IpHeader>,
+    pub header: Option<TcpHeader>,
     pub data: Vec<u8>,
 }
 
-pub struct TcpSession<'a> {
-    pub local_addr: &'a str,
-    pub local_ipv4: [u8; 4], 
-    pub local_port: u16,
-    pub remote_addr: Option<&'a str>,
-    pub remote_ipv4: Option<[u8; 4]>,
-    pub remote_port: Option<u16>,
-    listener: TcpListener,
+impl TcpDatagram {
+    /// Wraps the bytes of a single stream read.
+    pub(crate) fn plain(bytes: &[u8]) -> TcpDatagram {
+        TcpDatagram {
+            ip: None,
+            header: None,
+            data: bytes.to_vec(),
+        }
+    }
+
+    /// Decodes a segment read off a raw socket.
+    pub(crate) fn decode(ip: Option<IpHeader>, bytes: &[u8]) -> Result<TcpDatagram, Error> {
+        let (header, data) =
+            TcpHeader::read_from_slice(bytes).map_err(|source| Error::HeaderDecode {
+                protocol: Protocol::Tcp,
+                source,
+            })?;
+
+        Ok(TcpDatagram {
+            ip,
+            header: Some(header),
+            data: data.to_vec(),
+        })
+    }
+}
+
+impl Datagram for TcpDatagram {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn ip_header(&self) -> Option<&IpHeader> {
+        self.ip.as_ref()
+    }
+}
+
+/// A TCP connection, or in raw mode a raw socket that sends crafted
+/// segments without ever connecting.
+pub struct TcpSession {
+    pub local: SocketAddr,
+    pub remote: Option<SocketAddr>,
+    listener: Option<TcpListener>,
     socket: Option<TcpStream>,
+    raw: Option<RawSocket>,
+    options: SocketOptions,
 }
 
-impl<'a> TcpSession<'a> {
-    pub fn new(local: &'a str) -> TcpSession {
-        let listener = TcpListener::bind(local).unwrap();
-
-        let (ipv4, port) = local.split_once(':').unwrap();
-        let local_ipv4 = Ipv4Addr::from_str(&ipv4).unwrap().octets();
-        let local_port = u16::from_str(&port).unwrap();
-
-        TcpSession {
-            local_addr: &*local,
-            local_ipv4,
-            local_port,
-            remote_addr: None,
-            remote_ipv4: None,
-            remote_port: None,
+impl TcpSession {
+    /// Listens on `local` with default socket options; see `SessionBuilder`.
+    pub fn new(local: SocketAddr) -> Result<TcpSession, Error> {
+        TcpSession::with_options(local, SocketOptions::default())
+    }
+
+    pub(crate) fn with_options(
+        local: SocketAddr,
+        options: SocketOptions,
+    ) -> Result<TcpSession, Error> {
+        if options.gro || options.gso {
+            return Err(Error::Unsupported("UDP offload in TCP sessions"));
+        }
+        let (listener, raw) = if options.raw {
+            (None, Some(RawSocket::new(local, Protocol::Tcp)?))
+        } else {
+            (Some(options.tcp_listener(local)?), None)
+        };
+
+        Ok(TcpSession {
+            local,
+            remote: None,
             listener,
             socket: None,
-        }
+            raw,
+            options,
+        })
     }
 
-    pub fn connect_to(&mut self, remote: &'a str) -> Result<(), IoErr> {
-        self.remote_addr = Some(remote);
-        let (ipv4, port) = remote.split_once(':').unwrap();
-        self.remote_ipv4 = Some(Ipv4Addr::from_str(&ipv4).unwrap().octets());
-        self.remote_port = Some(u16::from_str(&port).unwrap());
-        self.socket = Some(TcpStream::connect(remote)?);
+    /// Connects to `remote`; in raw mode this only records it.
+    pub fn connect_to(&mut self, remote: SocketAddr) -> Result<(), Error> {
+        self.remote = Some(remote);
+        if self.raw.is_none() {
+            self.socket = Some(self.options.tcp_stream(remote)?);
+        }
         Ok(())
     }
 
-    pub fn wait_for_connection(&mut self, wait_time: u32) -> Result<u32, IoErr> {
+    /// The header fields used for raw sends, which can be changed
+    /// between them.
+    pub fn packet_options(&mut self) -> &mut PacketOptions {
+        &mut self.options.packet
+    }
+
+    pub fn wait_for_connection(&mut self, wait_time: Duration) -> Result<Duration, Error> {
+        let listener = self.listener.as_ref().ok_or(Error::NotConnected)?;
         let time = Instant::now();
-        self.listener.set_nonblocking(true)?;
-        while time.elapsed().as_secs() < wait_time.into() {
-            match self.listener.accept() {
-                Ok((new_socket, _addr)) => {
+        listener.set_nonblocking(true)?;
+        loop {
+            let remaining = wait_time.saturating_sub(time.elapsed());
+            if !wait_readable(listener, remaining)? {
+                return Err(Error::Timeout(wait_time));
+            }
+            match listener.accept() {
+                Ok((new_socket, addr)) => {
+                    new_socket.set_nonblocking(false)?;
+                    self.options.configure_stream(&new_socket)?;
+                    self.remote = Some(addr);
                     self.socket = Some(new_socket);
                     break;
                 }
-                Err(ref e) if e.kind() == IoErrKind::WouldBlock => {
-                    if time.elapsed().as_secs() >= wait_time.into() {
-                        return Err(IoErr::from(IoErrKind::TimedOut));
-                    }
-                }
-                Err(e) => return Err(e),
+                // Someone else took the connection first.
+                Err(ref e) if e.kind() == IoErrKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
             }
         }
-        Ok(time.elapsed().as_secs() as u32)
+        Ok(time.elapsed())
+    }
+}
+
+/// Builds the packet sent from `local` to `remote` carrying `payload`.
+pub(crate) fn encode(
+    local: SocketAddr,
+    remote: SocketAddr,
+    options: &PacketOptions,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let ip = options.ip_builder(local.ip(), remote.ip())?;
+    let builder = options.tcp_builder(ip, local.port(), remote.port());
+
+    let mut packet = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload)?;
+    options.route(&mut packet);
+    Ok(packet)
+}
+
+impl Sender for TcpSession {
+    fn send(&mut self, payload: &[u8]) -> Result<usize, Error> {
+        let remote = self.remote.ok_or(Error::NoRemote)?;
+        if let Some(raw) = &self.raw {
+            let packet = encode(self.local, remote, &self.options.packet, payload)?;
+            let bytes = raw.send(&packet)?;
+            self.options.packet.advance(payload.len());
+            return Ok(bytes);
+        }
+
+        let socket = self.socket.as_ref().ok_or(Error::NotConnected)?;
+        (&*socket).write_all(payload)?;
+        Ok(payload.len())
+    }
+}
+
+/// Reads one chunk from a nonblocking `socket`, waiting at most
+/// `wait_time` for it to become readable.
+fn read_within(
+    socket: &TcpStream,
+    buf: &mut [u8],
+    wait_time: Duration,
+) -> Result<(TcpDatagram, Duration), Error> {
+    let now = Instant::now();
+    loop {
+        if !wait_readable(socket, wait_time.saturating_sub(now.elapsed()))? {
+            return Err(Error::Timeout(wait_time));
+        }
+        match (&*socket).read(buf) {
+            Ok(bytes) => return Ok((TcpDatagram::plain(&buf[..bytes]), now.elapsed())),
+            // Spurious wakeup; go back to sleep.
+            Err(ref e) if e.kind() == IoErrKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
     }
+}
+
+impl Receiver for TcpSession {
+    type Datagram = TcpDatagram;
 
     #[inline]
-    pub fn receive(
-        &mut self,
-        wait_time: u32,
-    ) -> Result<(TcpDatagram, u32), IoErr> {
-        if self.socket.is_none() {
-            return Err(IoErr::from(IoErrKind::NotConnected));
+    fn receive(&mut self, wait_time: Duration) -> Result<(TcpDatagram, Duration), Error> {
+        if let Some(raw) = &mut self.raw {
+            let now = Instant::now();
+            let (ip, segment) = raw.receive(wait_time)?;
+            return Ok((TcpDatagram::decode(ip, &segment)?, now.elapsed()));
         }
 
+        let socket = self.socket.as_ref().ok_or(Error::NotConnected)?;
+
         let buf: &mut [u8] = &mut [0u8; 65536];
 
-        self.socket.as_ref().unwrap().set_nonblocking(true).unwrap();
-        let now = Instant::now();
-        loop {
-            match self.socket.as_ref().unwrap().read(&mut buf[..]) {
-                Ok(bytes) => {
-                        let read_time = now.elapsed().as_millis() as u32;
-                        let (header, data) = TcpHeader::read_from_slice(&buf)
-                            .expect("`TcpHeader::read_from_slice()` failed!");
+        // Nonblocking only for the read, so later sends still block
+        // until the whole payload is queued.
+        socket.set_nonblocking(true)?;
+        let result = read_within(socket, buf, wait_time);
+        socket.set_nonblocking(false)?;
+        result
+    }
+}
 
-                        let mut data = data.to_vec();
-                        data.resize(bytes - 8, 0);
+impl Session for TcpSession {
+    const PROTOCOL: Protocol = Protocol::Tcp;
 
-                        let datagram = (TcpDatagram {header, data}, read_time);
-                        return Ok(datagram);
-                }
-                Err(ref e) if e.kind() == IoErrKind::WouldBlock => {
-                    if now.elapsed().as_secs() >= wait_time.into() {
-                        return Err(IoErr::from(IoErrKind::TimedOut));
-                    }
-                }
-                Err(e) => return Err(e),
-            }
+    fn build(builder: &SessionBuilder) -> Result<Self, Error> {
+        builder.tcp()
+    }
+
+    fn accept(&mut self, wait_time: Duration) -> Result<Option<Duration>, Error> {
+        if self.raw.is_some() {
+            return Ok(None);
         }
+        self.wait_for_connection(wait_time).map(Some)
     }
 }
 
-impl<'a> Sender<'a> for TcpSession<'a> {
-    fn send(&mut self, payload: &'a [u8]) -> Result<usize, IoErr> {
-        let builder = PacketBuilder::ipv4(
-            self.local_ipv4,
-            self.remote_ipv4
-                .expect("No destination address specified in `Sender::send()`!"),
-            20, //FIXME allow user to specify TTL
-        )
-        .tcp(
-            self.local_port,
-            self.remote_port
-                .expect("No destination port specified in `Sender::send()`!"),
-            1, // Sequence Number
-            u16::MAX, // Window Size
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipopt::{IpOption, IpOptions};
+    use crate::packet::TcpFlags;
+
+    /// Encodes a segment and reads it back the way a raw session would.
+    fn round_trip(local: &str, remote: &str, options: &PacketOptions) -> TcpDatagram {
+        let (local, remote) = (local.parse().unwrap(), remote.parse().unwrap());
+        let packet = encode(local, remote, options, b"payload").unwrap();
+        let (ip, transport) = IpHeader::read_from_slice(&packet).unwrap();
+        TcpDatagram::decode(Some(ip), transport).unwrap()
+    }
+
+    #[test]
+    fn ipv4_segment_round_trips() {
+        let options = PacketOptions {
+            ttl: Some(7),
+            tos: 0b1011_1001,
+            identification: 4242,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..TcpFlags::default()
+            },
+            sequence_number: 1000,
+            acknowledgment_number: 2000,
+            window_size: 512,
+            ip_options: IpOptions(vec![IpOption::RecordRoute {
+                slots: 2,
+                recorded: Vec::new(),
+            }]),
+            ..PacketOptions::default()
+        };
+        let datagram = round_trip("10.0.0.1:4000", "10.0.0.2:80", &options);
+
+        let ip = match datagram.ip.as_ref().unwrap() {
+            IpHeader::Version4(ip) => ip,
+            other => panic!("expected IPv4, got {:?}", other),
+        };
+        assert_eq!(ip.source, [10, 0, 0, 1]);
+        assert_eq!(ip.destination, [10, 0, 0, 2]);
+        assert_eq!(ip.time_to_live, 7);
+        assert_eq!(ip.differentiated_services_code_point, 0b10_1110);
+        assert_eq!(ip.explicit_congestion_notification, 0b01);
+        assert_eq!(ip.identification, 4242);
+        assert_eq!(ip.protocol, 6);
+        assert_eq!(ip.header_checksum, ip.calc_header_checksum().unwrap());
+        assert_eq!(IpOptions::decode(ip.options()).unwrap(), options.ip_options);
+
+        let tcp = datagram.header.as_ref().unwrap();
+        assert_eq!((tcp.source_port, tcp.destination_port), (4000, 80));
+        assert_eq!(tcp.sequence_number, 1000);
+        assert_eq!(tcp.acknowledgment_number, 2000);
+        assert_eq!(tcp.window_size, 512);
+        assert!(tcp.syn && tcp.ack && !tcp.fin && !tcp.rst && !tcp.psh && !tcp.urg);
+        assert_eq!(
+            tcp.checksum,
+            tcp.calc_checksum_ipv4(ip, b"payload").unwrap()
         );
+        assert_eq!(datagram.data, b"payload");
+    }
 
-        let mut packet = Vec::<u8>::with_capacity(builder.size(payload.len()));
-        builder.write(&mut packet, &payload).unwrap();
+    #[test]
+    fn ipv6_segment_round_trips() {
+        let options = PacketOptions {
+            ttl: Some(3),
+            tos: 0x20,
+            flags: TcpFlags {
+                fin: true,
+                psh: true,
+                urg: true,
+                ..TcpFlags::default()
+            },
+            urgent_pointer: 5,
+            ..PacketOptions::default()
+        };
+        let datagram = round_trip("[fd00::1]:4000", "[fd00::2]:443", &options);
 
-        let mut bytes: usize = 0;
-        if let Some(_socket) = &self.socket {
-            bytes = self.socket.as_ref().unwrap().write(&packet)?;
-        }
-        Ok(bytes)
+        let ip = match datagram.ip.as_ref().unwrap() {
+            IpHeader::Version6(ip) => ip,
+            other => panic!("expected IPv6, got {:?}", other),
+        };
+        assert_eq!(
+            ip.source,
+            "fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(
+            ip.destination,
+            "fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(ip.hop_limit, 3);
+        assert_eq!(ip.traffic_class, 0x20);
+
+        let tcp = datagram.header.as_ref().unwrap();
+        assert_eq!((tcp.source_port, tcp.destination_port), (4000, 443));
+        assert!(tcp.fin && tcp.psh && tcp.urg && !tcp.syn && !tcp.ack);
+        assert_eq!(tcp.urgent_pointer, 5);
+        assert_eq!(
+            tcp.checksum,
+            tcp.calc_checksum_ipv6(ip, b"payload").unwrap()
+        );
+        assert_eq!(datagram.data, b"payload");
+    }
+
+    #[test]
+    fn truncated_segment_is_a_decode_error() {
+        let result = TcpDatagram::decode(None, &[0u8; 10]);
+        assert!(matches!(
+            result,
+            Err(Error::HeaderDecode {
+                protocol: Protocol::Tcp,
+                ..
+            })
+        ));
     }
 }
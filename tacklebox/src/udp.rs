@@ -1,106 +1,484 @@
 extern crate etherparse;
-use etherparse::UdpHeader;
-use etherparse::PacketBuilder;
-use std::io::ErrorKind as IoErrKind;
+use etherparse::{IpHeader, UdpHeader};
+use std::collections::VecDeque;
 use std::io::Error as IoErr;
-use std::net::Ipv4Addr;
+use std::io::ErrorKind as IoErrKind;
+use std::net::SocketAddr;
 use std::net::UdpSocket;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::Sender;
-
-use std::str::FromStr;
+use crate::batch::{self, BufferPool};
+use crate::errqueue;
+use crate::packet::PacketOptions;
+use crate::poll::wait_readable;
+use crate::raw::RawSocket;
+use crate::session::SocketOptions;
+use crate::{Datagram, Error, Protocol, Receiver, Sender, Session, SessionBuilder};
 
+/// What a single read returned. The headers are only there for
+/// packets read off a raw socket; see `SessionBuilder::raw`.
+#[derive(Debug, Clone)]
 pub struct UdpDatagram {
-    pub header: UdpHeader,
+    pub ip: Option<IpHeader>,
+    pub header: Option<UdpHeader>,
     pub data: Vec<u8>,
 }
 
-pub struct UdpSession<'a> {
-    pub local_addr: &'a str,
-    pub local_ipv4: [u8; 4],
-    pub local_port: u16,
-    pub remote_addr: Option<&'a str>,
-    pub remote_ipv4: Option<[u8; 4]>,
-    pub remote_port: Option<u16>,
+impl UdpDatagram {
+    /// Wraps the bytes of a single socket read.
+    pub(crate) fn plain(bytes: &[u8]) -> UdpDatagram {
+        UdpDatagram {
+            ip: None,
+            header: None,
+            data: bytes.to_vec(),
+        }
+    }
+
+    /// Decodes a datagram read off a raw socket.
+    pub(crate) fn decode(ip: Option<IpHeader>, bytes: &[u8]) -> Result<UdpDatagram, Error> {
+        let (header, data) =
+            UdpHeader::read_from_slice(bytes).map_err(|source| Error::HeaderDecode {
+                protocol: Protocol::Udp,
+                source,
+            })?;
+
+        Ok(UdpDatagram {
+            ip,
+            header: Some(header),
+            data: data.to_vec(),
+        })
+    }
+}
+
+impl Datagram for UdpDatagram {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn ip_header(&self) -> Option<&IpHeader> {
+        self.ip.as_ref()
+    }
+}
+
+/// A UDP socket, or in raw mode a raw socket that sends crafted
+/// datagrams. The UDP socket stays bound in raw mode so the kernel
+/// doesn't answer what arrives on our port with port unreachable.
+pub struct UdpSession {
+    pub local: SocketAddr,
+    pub remote: Option<SocketAddr>,
     socket: UdpSocket,
+    raw: Option<RawSocket>,
+    packet: PacketOptions,
+    recv_pool: Option<BufferPool>,
+    send_packets: Vec<Vec<u8>>,
+    // Datagrams from a coalesced read that the caller has not taken yet.
+    pending: VecDeque<UdpDatagram>,
+    gso: bool,
+    gro: bool,
+    connected: bool,
 }
 
-impl<'a> UdpSession<'a> {
-    pub fn new(local: &'a str) -> Self {
-        let socket = UdpSocket::bind(local).expect("bind() failed!");
-
-        let (ipv4, port) = local.split_once(':').unwrap();
-        let local_ipv4 = Ipv4Addr::from_str(&ipv4).unwrap().octets();
-        let local_port = u16::from_str(&port).unwrap();
-
-        UdpSession {
-            local_addr: &*local,
-            local_ipv4,
-            local_port,
-            remote_addr: None,
-            remote_ipv4: None,
-            remote_port: None,
+impl UdpSession {
+    /// Binds `local` with default socket options; see `SessionBuilder`.
+    pub fn new(local: SocketAddr) -> Result<Self, Error> {
+        UdpSession::with_options(local, SocketOptions::default())
+    }
+
+    pub(crate) fn with_options(local: SocketAddr, options: SocketOptions) -> Result<Self, Error> {
+        // Raw sends and reads go around the UDP socket the kernel would
+        // segment or coalesce on.
+        if options.raw && (options.gro || options.gso) {
+            return Err(Error::Unsupported("UDP offload with raw sockets"));
+        }
+        let socket = options.udp_socket(local)?;
+        let raw = if options.raw {
+            Some(RawSocket::new(local, Protocol::Udp)?)
+        } else {
+            None
+        };
+
+        Ok(UdpSession {
+            local,
+            remote: None,
             socket,
+            raw,
+            packet: options.packet,
+            recv_pool: None,
+            send_packets: Vec::new(),
+            pending: VecDeque::new(),
+            gso: options.gso,
+            gro: options.gro,
+            connected: false,
+        })
+    }
+
+    pub fn set_remote(&mut self, remote: SocketAddr) {
+        self.remote = Some(remote);
+    }
+
+    /// Connects the socket to `remote`, so only its datagrams are
+    /// received and any ICMP error it sends back, such as port
+    /// unreachable, fails the next send or receive with
+    /// `Error::Connect`. No root is needed for this.
+    pub fn connect(&mut self, remote: SocketAddr) -> Result<(), Error> {
+        self.socket
+            .connect(remote)
+            .map_err(|source| Error::Connect {
+                addr: remote,
+                source,
+            })?;
+        errqueue::enable(&self.socket, self.local)?;
+        self.remote = Some(remote);
+        self.connected = true;
+        Ok(())
+    }
+
+    /// Turns an ICMP error reported on a connected socket into
+    /// `Error::Connect`, taking the detailed one off the error queue
+    /// if the kernel queued it.
+    fn peer_error(&self, e: IoErr) -> Error {
+        match self.remote {
+            Some(addr) if self.connected && errqueue::is_unreachable(&e) => {
+                let source = errqueue::take(&self.socket).ok().flatten().unwrap_or(e);
+                Error::Connect { addr, source }
+            }
+            _ => e.into(),
+        }
+    }
+
+    /// A queued ICMP error, for when the socket woke up without data.
+    fn queued_error(&self) -> Result<(), Error> {
+        match (self.remote, self.connected) {
+            (Some(addr), true) => match errqueue::take(&self.socket)? {
+                Some(source) => Err(Error::Connect { addr, source }),
+                None => Ok(()),
+            },
+            _ => Ok(()),
         }
     }
 
-    pub fn set_remote(&mut self, remote: &'a str) {
-        self.remote_addr = Some(remote);
-        let (ipv4, port) = remote.split_once(':').unwrap();
-        self.remote_ipv4 = Some(Ipv4Addr::from_str(&ipv4).unwrap().octets());
-        self.remote_port = Some(u16::from_str(&port).unwrap());
+    /// The header fields used for raw sends, which can be changed
+    /// between them.
+    pub fn packet_options(&mut self) -> &mut PacketOptions {
+        &mut self.packet
     }
 
+    /// Waits up to `wait_time` for a datagram, then fills `pool` with
+    /// as many as are queued using a single `recvmmsg` where available.
+    pub fn receive_batch(
+        &mut self,
+        pool: &mut BufferPool,
+        wait_time: Duration,
+    ) -> Result<usize, Error> {
+        let now = Instant::now();
+        loop {
+            if !wait_readable(&self.socket, wait_time.saturating_sub(now.elapsed()))? {
+                return Err(Error::Timeout(wait_time));
+            }
+            match batch::recv_batch(&self.socket, pool) {
+                Ok(received) => return Ok(received),
+                Err(ref e) if e.kind() == IoErrKind::WouldBlock => self.queued_error()?,
+                Err(e) => return Err(self.peer_error(e)),
+            }
+        }
+    }
+
+    /// The receive pool from the last batch if it holds `max` reads,
+    /// or a new one.
+    fn take_pool(&mut self, max: usize) -> BufferPool {
+        match self.recv_pool.take() {
+            Some(pool) if pool.capacity() == max => pool,
+            _ => BufferPool::new(max, 65536),
+        }
+    }
+
+    /// Sends every payload to the remote using a single `sendmmsg`
+    /// where available, returning how many were sent. With GSO enabled
+    /// the kernel segments each run of equally sized packets itself.
+    pub fn send_batch(&mut self, payloads: &[&[u8]]) -> Result<usize, Error> {
+        let remote = self.remote.ok_or(Error::NoRemote)?;
+
+        // Reuse the send buffers from the last batch.
+        self.send_packets.resize_with(payloads.len(), Vec::new);
+        for (packet, payload) in self.send_packets.iter_mut().zip(payloads) {
+            packet.clear();
+            packet.extend_from_slice(payload);
+        }
+
+        self.socket.set_nonblocking(false)?;
+        let packets = &self.send_packets[..payloads.len()];
+        #[cfg(target_os = "linux")]
+        let sent = if self.gso {
+            batch::send_gso(&self.socket, packets, remote)?
+        } else {
+            batch::send_batch(&self.socket, packets, remote)?
+        };
+        #[cfg(not(target_os = "linux"))]
+        let sent = batch::send_batch(&self.socket, packets, remote)?;
+        Ok(sent)
+    }
+}
+
+/// Builds the packet sent from `local` to `remote` carrying `payload`.
+pub(crate) fn encode(
+    local: SocketAddr,
+    remote: SocketAddr,
+    options: &PacketOptions,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let builder = options
+        .ip_builder(local.ip(), remote.ip())?
+        .udp(local.port(), remote.port());
+
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload)?;
+    options.route(&mut packet);
+    Ok(packet)
+}
+
+impl Sender for UdpSession {
+    fn send(&mut self, payload: &[u8]) -> Result<usize, Error> {
+        let remote = self.remote.ok_or(Error::NoRemote)?;
+        if let Some(raw) = &self.raw {
+            let packet = encode(self.local, remote, &self.packet, payload)?;
+            let bytes = raw.send(&packet)?;
+            self.packet.advance(payload.len());
+            return Ok(bytes);
+        }
+
+        let bytes = self
+            .socket
+            .send_to(payload, remote)
+            .map_err(|e| self.peer_error(e))?;
+        Ok(bytes)
+    }
+
+    fn send_many(&mut self, payloads: &[&[u8]]) -> Result<usize, Error> {
+        if self.raw.is_some() {
+            for payload in payloads {
+                self.send(payload)?;
+            }
+            return Ok(payloads.len());
+        }
+        self.send_batch(payloads)
+    }
+}
+
+impl Receiver for UdpSession {
+    type Datagram = UdpDatagram;
+
     #[inline]
-    pub fn receive(&self, wait_time: u32) -> Result<(UdpDatagram, u32), IoErr> {
+    fn receive(&mut self, wait_time: Duration) -> Result<(UdpDatagram, Duration), Error> {
+        if let Some(datagram) = self.pending.pop_front() {
+            return Ok((datagram, Duration::ZERO));
+        }
+        if let Some(raw) = &mut self.raw {
+            let now = Instant::now();
+            let (ip, datagram) = raw.receive(wait_time)?;
+            return Ok((UdpDatagram::decode(ip, &datagram)?, now.elapsed()));
+        }
+        if self.gro {
+            // A single read may hold several datagrams; keep the rest.
+            let now = Instant::now();
+            let datagram = self.receive_many(1, wait_time)?.remove(0);
+            return Ok((datagram, now.elapsed()));
+        }
+
         let buf: &mut [u8] = &mut [0u8; 65536];
 
-        self.socket.set_nonblocking(true).unwrap();
+        self.socket.set_nonblocking(true)?;
         let now = Instant::now();
         loop {
-                match self.socket.recv(&mut buf[..]) {
+            if !wait_readable(&self.socket, wait_time.saturating_sub(now.elapsed()))? {
+                return Err(Error::Timeout(wait_time));
+            }
+            match self.socket.recv(&mut buf[..]) {
                 Ok(bytes) => {
-                    let read_time = now.elapsed().as_millis() as u32;
-                    let (header, data) = UdpHeader::read_from_slice(&buf)
-                        .expect("`UdpHeader::read_from_slice()` failed!");
-
-                    let mut data = data.to_vec();
-                    data.resize(bytes - 8, 0);
-
-                    let datagram = (UdpDatagram{header, data}, read_time);
-                    return Ok(datagram);
+                    let read_time = now.elapsed();
+                    return Ok((UdpDatagram::plain(&buf[..bytes]), read_time));
                 }
-                Err(ref e) if e.kind() == IoErrKind::WouldBlock => {
-                    if now.elapsed().as_secs() >= wait_time as u64 {
-                        return Err(IoErr::from(IoErrKind::TimedOut));
-                    }
-                }
-                Err(e) => return Err(e),
+                // Spurious wakeup, or an ICMP error with no data.
+                Err(ref e) if e.kind() == IoErrKind::WouldBlock => self.queued_error()?,
+                Err(e) => return Err(self.peer_error(e)),
+            }
+        }
+    }
+
+    fn receive_many(&mut self, max: usize, wait_time: Duration) -> Result<Vec<UdpDatagram>, Error> {
+        if self.raw.is_some() && self.pending.is_empty() {
+            let (datagram, _read_time) = self.receive(wait_time)?;
+            return Ok(vec![datagram]);
+        }
+        if self.pending.is_empty() {
+            let mut pool = self.take_pool(max);
+            let received = self.receive_batch(&mut pool, wait_time);
+            if received.is_ok() {
+                self.pending.extend(pool.datagrams());
             }
+            self.recv_pool = Some(pool);
+            received?;
         }
+
+        // Coalesced reads can hold more than `max` datagrams.
+        let count = max.min(self.pending.len());
+        Ok(self.pending.drain(..count).collect())
+    }
+
+    fn receive_count(&mut self, max: usize, wait_time: Duration) -> Result<(usize, usize), Error> {
+        if self.raw.is_some() || !self.pending.is_empty() {
+            let datagrams = self.receive_many(max, wait_time)?;
+            return Ok((datagrams.len(), datagrams.iter().map(Datagram::len).sum()));
+        }
+
+        let mut pool = self.take_pool(max);
+        let received = self.receive_batch(&mut pool, wait_time);
+        let counted = received.map(|_| {
+            let count = max.min(pool.len());
+            let bytes = pool.iter().take(count).map(<[u8]>::len).sum();
+            // Only datagrams past `max` from a coalesced read are copied,
+            // to be handed out next time.
+            self.pending
+                .extend(pool.iter().skip(count).map(UdpDatagram::plain));
+            (count, bytes)
+        });
+        self.recv_pool = Some(pool);
+        counted
+    }
+}
+
+impl Session for UdpSession {
+    const PROTOCOL: Protocol = Protocol::Udp;
+
+    fn build(builder: &SessionBuilder) -> Result<Self, Error> {
+        builder.udp()
+    }
+
+    fn accept(&mut self, _wait_time: Duration) -> Result<Option<Duration>, Error> {
+        Ok(None)
     }
 }
 
-impl<'a> Sender<'a> for UdpSession<'a> {
-    fn send(&mut self, payload: &'a [u8]) -> Result<usize, IoErr> {
-        let builder = PacketBuilder::ipv4(
-            self.local_ipv4,
-            self.remote_ipv4
-                .expect("No destination address specified in `Sender::send()`!"),
-            20, //FIXME allow user to specify TTL
-        )
-        .udp(
-            self.local_port,
-            self.remote_port
-                .expect("No destination port specified in `Sender::send()`!"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipopt::{IpOption, IpOptions};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// A sender and receiver on loopback ports picked by the kernel.
+    fn pair(sender: SessionBuilder, receiver: SessionBuilder) -> (UdpSession, UdpSession) {
+        let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let mut sender = sender.local(any).udp().unwrap();
+        let mut receiver = receiver.local(any).udp().unwrap();
+        receiver.local = receiver.socket.local_addr().unwrap();
+        sender.set_remote(receiver.local);
+        (sender, receiver)
+    }
+
+    #[test]
+    fn receive_count_measures_the_batch() {
+        let (mut sender, mut receiver) = pair(SessionBuilder::new(), SessionBuilder::new());
+        sender.send_many(&[b"one", b"three", b"fives"]).unwrap();
+
+        let wait = Duration::from_secs(1);
+        assert_eq!(receiver.receive_count(2, wait).unwrap(), (2, 8));
+        assert_eq!(receiver.receive_count(2, wait).unwrap(), (1, 5));
+        assert!(receiver.pending.is_empty());
+        assert!(matches!(
+            receiver.receive_count(2, Duration::from_millis(20)),
+            Err(Error::Timeout(_))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn receive_count_keeps_coalesced_datagrams_past_max() {
+        let (mut sender, mut receiver) = pair(
+            SessionBuilder::new().gso(true),
+            SessionBuilder::new().gro(true),
         );
+        // One GSO send that reaches the GRO socket as a single read.
+        sender.send_many(&[b"ab", b"cd", b"ef"]).unwrap();
+
+        let wait = Duration::from_secs(1);
+        assert_eq!(receiver.receive_count(2, wait).unwrap(), (2, 4));
+        assert_eq!(receiver.pending.len(), 1);
+        assert_eq!(receiver.receive_count(2, wait).unwrap(), (1, 2));
+        assert!(receiver.pending.is_empty());
+    }
 
-        let mut packet = Vec::<u8>::with_capacity(builder.size(payload.len()));
-        builder.write(&mut packet, &payload).unwrap();
+    #[test]
+    fn source_routed_datagram_round_trips() {
+        let options = PacketOptions {
+            ttl: Some(9),
+            ip_options: IpOptions(vec!["loose:10.0.0.7,10.0.0.8".parse().unwrap()]),
+            ..PacketOptions::default()
+        };
+        let local = "10.0.0.1:5353".parse().unwrap();
+        let remote = "10.0.0.2:53".parse().unwrap();
+        let packet = encode(local, remote, &options, b"query").unwrap();
 
-        let remote_addr = &self.remote_addr.unwrap();
-        let bytes = self.socket.send_to(&packet, remote_addr)?;
-        Ok(bytes)
+        let (ip, transport) = IpHeader::read_from_slice(&packet).unwrap();
+        let datagram = UdpDatagram::decode(Some(ip), transport).unwrap();
+        let ip = match datagram.ip.as_ref().unwrap() {
+            IpHeader::Version4(ip) => ip,
+            other => panic!("expected IPv4, got {:?}", other),
+        };
+        // Sent to the first hop, with the rest of the route and then
+        // the destination in the option.
+        assert_eq!(ip.destination, [10, 0, 0, 7]);
+        assert_eq!(ip.time_to_live, 9);
+        assert_eq!(ip.protocol, 17);
+        assert_eq!(ip.header_checksum, ip.calc_header_checksum().unwrap());
+        let route = vec!["10.0.0.8".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        assert_eq!(
+            IpOptions::decode(ip.options()).unwrap(),
+            IpOptions(vec![IpOption::LooseSourceRoute(route)])
+        );
+
+        let udp = datagram.header.as_ref().unwrap();
+        assert_eq!((udp.source_port, udp.destination_port), (5353, 53));
+        assert_eq!(usize::from(udp.length), 8 + b"query".len());
+        // The checksum covers the final destination, not the first hop.
+        let checksum = udp
+            .calc_checksum_ipv4_raw([10, 0, 0, 1], [10, 0, 0, 2], 17, b"query")
+            .unwrap();
+        assert_eq!(udp.checksum, checksum);
+        assert_eq!(datagram.data, b"query");
+    }
+
+    #[test]
+    fn ipv6_datagram_round_trips() {
+        let options = PacketOptions {
+            tos: 0x48,
+            ..PacketOptions::default()
+        };
+        let local = "[fd00::1]:5353".parse().unwrap();
+        let remote = "[fd00::2]:53".parse().unwrap();
+        let packet = encode(local, remote, &options, b"query").unwrap();
+
+        let (ip, transport) = IpHeader::read_from_slice(&packet).unwrap();
+        let datagram = UdpDatagram::decode(Some(ip), transport).unwrap();
+        let ip = match datagram.ip.as_ref().unwrap() {
+            IpHeader::Version6(ip) => ip,
+            other => panic!("expected IPv6, got {:?}", other),
+        };
+        assert_eq!(ip.traffic_class, 0x48);
+        assert_eq!(ip.next_header, 17);
+        let udp = datagram.header.as_ref().unwrap();
+        assert_eq!((udp.source_port, udp.destination_port), (5353, 53));
+        assert_eq!(udp.checksum, udp.calc_checksum_ipv6(ip, b"query").unwrap());
+        assert_eq!(datagram.data, b"query");
+    }
+
+    #[test]
+    fn ip_options_over_ipv6_are_unsupported() {
+        let options = PacketOptions {
+            ip_options: IpOptions(vec!["record".parse().unwrap()]),
+            ..PacketOptions::default()
+        };
+        let local = "[fd00::1]:5353".parse().unwrap();
+        let remote = "[fd00::2]:53".parse().unwrap();
+        let result = encode(local, remote, &options, b"query");
+        assert!(matches!(result, Err(Error::Unsupported(_))));
     }
 }
@@ -0,0 +1,117 @@
+//! Many async sessions sharing one runtime.
+#![cfg(feature = "async")]
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tacklebox::{Datagram, Error, SessionBuilder};
+
+/// How many pairs to run at once, if the descriptor limit allows it.
+const PAIRS: usize = 2000;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+fn any_port() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+}
+
+/// Raises the soft descriptor limit as far as it goes, and returns how
+/// many pairs fit in it at `fds_per_pair` each.
+fn pairs(fds_per_pair: u64) -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        limit.rlim_cur = limit.rlim_max;
+        libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+    }
+    let spare = limit.rlim_cur.saturating_sub(64);
+    PAIRS.min((spare / fds_per_pair) as usize)
+}
+
+#[tokio::test]
+async fn thousands_of_tcp_sessions() {
+    // Each side has a listener, and each has a connected stream.
+    let pairs = pairs(4);
+    assert!(pairs >= 100, "descriptor limit too low to test with");
+
+    let tasks: Vec<_> = (0..pairs)
+        .map(|i| {
+            tokio::spawn(async move {
+                let mut server = SessionBuilder::new().local(any_port()).tcp_async().await?;
+                // The listener's backlog holds the connection until
+                // the server accepts it.
+                let mut client = SessionBuilder::new()
+                    .local(any_port())
+                    .remote(server.local)
+                    .tcp_async()
+                    .await?;
+                server.accept(WAIT).await?;
+
+                let request = format!("request {}", i);
+                client.send(request.as_bytes()).await?;
+                let (datagram, _) = server.receive(WAIT).await?;
+                assert_eq!(datagram.data(), request.as_bytes());
+
+                let reply = format!("reply {}", i);
+                server.send(reply.as_bytes()).await?;
+                let (datagram, _) = client.receive(WAIT).await?;
+                assert_eq!(datagram.data(), reply.as_bytes());
+                Ok::<(), Error>(())
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn thousands_of_udp_sessions() {
+    let pairs = pairs(2);
+    assert!(pairs >= 100, "descriptor limit too low to test with");
+
+    let tasks: Vec<_> = (0..pairs)
+        .map(|i| {
+            tokio::spawn(async move {
+                let mut a = SessionBuilder::new().local(any_port()).udp_async().await?;
+                let mut b = SessionBuilder::new()
+                    .local(any_port())
+                    .remote(a.local)
+                    .udp_async()
+                    .await?;
+                a.set_remote(b.local);
+
+                let ping = format!("ping {}", i);
+                b.send(ping.as_bytes()).await?;
+                let (datagram, _) = a.receive(WAIT).await?;
+                assert_eq!(datagram.data(), ping.as_bytes());
+
+                let pong = format!("pong {}", i);
+                a.send(pong.as_bytes()).await?;
+                let (datagram, _) = b.receive(WAIT).await?;
+                assert_eq!(datagram.data(), pong.as_bytes());
+                Ok::<(), Error>(())
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn udp_offload_is_rejected() {
+    for builder in &[
+        SessionBuilder::new().gro(true),
+        SessionBuilder::new().gso(true),
+    ] {
+        let result = builder.clone().local(any_port()).udp_async().await;
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,133 @@
+//! Scanners run against sockets on loopback.
+
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::time::Duration;
+
+use tacklebox::scan::{ConnectScan, PortState, SynScan, UdpScan};
+use tacklebox::Error;
+
+/// A loopback port with nothing listening on it.
+fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// A loopback UDP port with nothing bound to it.
+fn closed_udp_port() -> SocketAddr {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.local_addr().unwrap()
+}
+
+#[test]
+fn connect_scan_reports_open_and_closed_in_order() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = closed_port();
+    let targets = [closed, open, closed, open];
+
+    let scan = ConnectScan::new()
+        .concurrency(2)
+        .timeout(Duration::from_secs(2));
+    let reports = scan.run(&targets).unwrap();
+
+    let addrs: Vec<SocketAddr> = reports.iter().map(|report| report.addr).collect();
+    assert_eq!(addrs, targets);
+    let states: Vec<PortState> = reports.iter().map(|report| report.state).collect();
+    assert_eq!(
+        states,
+        [
+            PortState::Closed,
+            PortState::Open,
+            PortState::Closed,
+            PortState::Open
+        ]
+    );
+    // Both answered, one way or the other.
+    assert!(reports.iter().all(|report| report.latency.is_some()));
+}
+
+/// Runs `f` on this thread without the privileges root has, so raw
+/// sockets can't be opened even when the tests run as root.
+///
+/// The raw `setresuid` syscall changes only the calling thread, unlike
+/// libc's, which would drop them for every test running alongside.
+#[cfg(target_os = "linux")]
+fn unprivileged<T>(f: impl FnOnce() -> T) -> T {
+    let nobody: libc::uid_t = 65534;
+    let keep = libc::uid_t::MAX;
+    let euid = unsafe { libc::geteuid() };
+    if euid == 0 {
+        let ret = unsafe { libc::syscall(libc::SYS_setresuid, keep, nobody, keep) };
+        assert_eq!(ret, 0, "couldn't drop privileges");
+    }
+    let result = f();
+    if euid == 0 {
+        unsafe { libc::syscall(libc::SYS_setresuid, keep, euid, keep) };
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn syn_scan_needs_a_raw_socket() {
+    let target = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+    let scan = SynScan::new().timeout(Duration::from_millis(50));
+    let result = unprivileged(|| scan.run(&[target]));
+    match result {
+        Err(Error::RawSocket(e)) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied)
+        }
+        other => panic!("expected Error::RawSocket, got {:?}", other),
+    }
+}
+
+#[test]
+fn udp_scan_tells_closed_from_silent() {
+    // Bound, but never answers.
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let open = silent.local_addr().unwrap();
+    let closed = closed_udp_port();
+
+    let scan = UdpScan::new()
+        .retries(1)
+        .timeout(Duration::from_millis(300));
+    let reports = scan.run(&[closed, open]).unwrap();
+
+    let states: Vec<(SocketAddr, PortState)> = reports
+        .iter()
+        .map(|report| (report.addr, report.state))
+        .collect();
+    assert_eq!(
+        states,
+        [(closed, PortState::Closed), (open, PortState::OpenFiltered)]
+    );
+    // Only the closed port answered, with a port unreachable.
+    assert!(reports[0].latency.is_some());
+    assert!(reports[1].latency.is_none());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn syn_scan_reports_open_and_closed() {
+    // Raw sockets need root; `syn_scan_needs_a_raw_socket` covers the
+    // rest.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = closed_port();
+
+    let scan = SynScan::new().timeout(Duration::from_secs(1));
+    let reports = scan.run(&[open, closed]).unwrap();
+
+    let states: Vec<(SocketAddr, PortState)> = reports
+        .iter()
+        .map(|report| (report.addr, report.state))
+        .collect();
+    assert_eq!(
+        states,
+        [(open, PortState::Open), (closed, PortState::Closed)]
+    );
+    assert!(reports.iter().all(|report| report.latency.is_some()));
+}